@@ -16,7 +16,10 @@ fn criterion_benchmark(c: &mut Criterion) {
                 let max_iter = 100;
                 let roots: Vec<Complex> = (0..5).map(|_| Complex { re: rng.gen(), im: rng.gen() }).collect();
                 let solutions = (0..(size*size))
-                    .map(|_| Solution {root: roots[rng.gen_range(0..roots.len())], iter: rng.gen_range(0..max_iter)})
+                    .map(|_| {
+                        let iter = rng.gen_range(0..max_iter);
+                        Solution {root: roots[rng.gen_range(0..roots.len())], iter, smooth: iter as f64}
+                    })
                     .collect();
                 let field = Field {
                     source: Complex { re: -5., im: -5. },
@@ -0,0 +1,117 @@
+use serde::Serialize;
+
+use crate::math::complex::Complex;
+use crate::math::polynomial::Polynomial;
+use crate::math::roots::{critical_points, roots, Root};
+use crate::{root_index, Solution};
+
+// what GET /roots returns, everything known about the polynomial
+// and how the field splits between the basins of its roots
+#[derive(Serialize, Debug)]
+pub struct Analysis {
+    pub polynomial: PolynomialInfo,
+    pub derivative: PolynomialInfo,
+    pub roots: Vec<RootInfo>,
+    pub critical_points: Vec<PointInfo>,
+    // fraction of the field that didn't converge to any of the roots within max_iter
+    pub not_converged: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PolynomialInfo {
+    // e.g. "-1 + x^3"
+    pub pretty: String,
+    pub coefficients: Vec<i32>,
+    pub degree: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RootInfo {
+    pub re: f64,
+    pub im: f64,
+    pub multiplicity: usize,
+    // fraction of the field converging to this root
+    pub basin: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PointInfo {
+    pub re: f64,
+    pub im: f64,
+    pub multiplicity: usize,
+}
+
+impl PolynomialInfo {
+    fn of(pol: &Polynomial) -> Self {
+        PolynomialInfo {
+            pretty: pol.to_string(),
+            coefficients: pol.coefficients().to_vec(),
+            degree: pol.degree(),
+        }
+    }
+}
+
+// solutions are the solved field, in the order of newton_method_field
+pub fn analyse(pol: &Polynomial, solutions: &[Solution], max_iter: u32) -> Analysis {
+    let roots = roots(pol);
+    let values: Vec<Complex> = roots.iter().map(|r| r.value).collect();
+
+    let mut basins = vec![0usize; roots.len()];
+    let mut not_converged = 0;
+    for solution in solutions {
+        let index = Some(solution)
+            .filter(|s| s.iter < max_iter)
+            .and_then(|s| root_index(&values, &s.root));
+        match index {
+            Some(i) => basins[i] += 1,
+            None => not_converged += 1,
+        }
+    }
+    let fraction = |count: usize| count as f64 / solutions.len().max(1) as f64;
+
+    Analysis {
+        polynomial: PolynomialInfo::of(pol),
+        derivative: PolynomialInfo::of(&pol.derivative()),
+        roots: roots
+            .iter()
+            .zip(basins)
+            .map(|(root, count)| RootInfo {
+                re: root.value.re,
+                im: root.value.im,
+                multiplicity: root.multiplicity,
+                basin: fraction(count),
+            })
+            .collect(),
+        critical_points: critical_points(pol).iter().map(point_info).collect(),
+        not_converged: fraction(not_converged),
+    }
+}
+
+fn point_info(root: &Root) -> PointInfo {
+    PointInfo {
+        re: root.value.re,
+        im: root.value.im,
+        multiplicity: root.multiplicity,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{newton_method_field, Field};
+
+    #[test]
+    fn symmetric_basins() {
+        // the basins of x^2 - 1 are the left and right half planes
+        let pol = Polynomial::new(vec![-1, 0, 1]);
+        let field = Field { source: Complex { re: -2.0, im: -2.0 }, size: 4.0, grid: 64 };
+        let analysis = analyse(&pol, &newton_method_field(&pol, &field, 50), 50);
+        assert_eq!("-1 + x^2", analysis.polynomial.pretty);
+        assert_eq!("2x", analysis.derivative.pretty);
+        assert_eq!(2, analysis.roots.len());
+        for root in &analysis.roots {
+            assert!((root.basin - 0.5).abs() < 0.02, "basin {}", root.basin);
+        }
+        assert_eq!(1, analysis.critical_points.len());
+    }
+}
@@ -0,0 +1,495 @@
+use std::f64::consts::PI;
+use std::sync::atomic::AtomicBool;
+
+use image::Rgb32FImage;
+use tracing::info_span;
+
+use crate::math::complex::{Complex, ZERO};
+use crate::math::polynomial::ComplexPolynomial;
+use crate::math::precision::{self, BigFloat, ExactPoint, Real};
+use crate::math::roots::all_roots;
+use crate::rendering::render_image_f32;
+use crate::request::{RenderRequest, REQUEST_METADATA_KEY};
+use crate::scene::{Coordinate, View};
+use crate::{newton_method_field_precise, root_index, Field, Solution};
+
+// milliseconds every frame is shown when the animation doesn't say
+pub const DEFAULT_DELAY_MS: u32 = 40;
+// gif keeps the delay in hundredths of a second in 16 bits
+pub const MAX_DELAY_MS: u32 = 655_350;
+
+// values that can be animated between two keyframes, t goes from 0 (self) to 1 (to)
+pub trait Interpolate: Clone {
+    fn interpolate(&self, to: &Self, t: f64) -> Self;
+}
+
+// the value at a given frame of the animation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe<T> {
+    pub frame: u32,
+    pub value: T,
+}
+
+// keyframes of an animation, the frames between two keyframes are interpolated
+// and the frames before the first and after the last one hold still
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeline<T> {
+    keyframes: Vec<Keyframe<T>>,
+}
+
+impl<T: Interpolate> Timeline<T> {
+    // none if there are no keyframes
+    pub fn new(mut keyframes: Vec<Keyframe<T>>) -> Option<Self> {
+        if keyframes.is_empty() {
+            return None;
+        }
+        keyframes.sort_by_key(|k| k.frame);
+        Some(Timeline { keyframes })
+    }
+
+    // an animation of the given number of frames going from one value to the other
+    pub fn between(from: T, to: T, frames: u32) -> Self {
+        let last = frames.saturating_sub(1);
+        Timeline {
+            keyframes: vec![Keyframe { frame: 0, value: from }, Keyframe { frame: last, value: to }],
+        }
+    }
+
+    // number of frames up to and including the last keyframe
+    pub fn frames(&self) -> u32 {
+        self.keyframes.last().map_or(0, |k| k.frame + 1)
+    }
+
+    pub fn at(&self, frame: u32) -> T {
+        let next = self.keyframes.iter().position(|k| k.frame > frame);
+        match next {
+            None => self.keyframes.last().unwrap().value.clone(),
+            Some(0) => self.keyframes[0].value.clone(),
+            Some(i) => {
+                let (from, to) = (&self.keyframes[i - 1], &self.keyframes[i]);
+                let t = (frame - from.frame) as f64 / (to.frame - from.frame) as f64;
+                from.value.interpolate(&to.value, t)
+            }
+        }
+    }
+}
+
+impl View {
+    pub fn field(&self, grid: u32) -> Field {
+        Field {
+            source: Complex { re: self.tx.value, im: self.ty.value },
+            size: self.tw,
+            grid,
+        }
+    }
+
+    // the corner with the digits an f64 can't hold, for views too deep for f64
+    pub fn exact_source(&self, field: &Field) -> Option<ExactPoint> {
+        ExactPoint::for_field(&self.tx.to_param(), &self.ty.to_param(), field)
+    }
+}
+
+// bits the corners of the views between from and to are interpolated with, enough to keep the
+// steps of the deepest view apart from the largest coordinate with room for any grid size
+fn interpolation_bits(from: &View, to: &View) -> usize {
+    let extent = [&from.tx, &from.ty, &to.tx, &to.ty]
+        .iter()
+        .fold(from.tw.max(to.tw), |extent, c| extent.max(c.value.abs()));
+    let bits = (extent / from.tw.min(to.tw)).log2() + 64.;
+    if bits.is_finite() {
+        bits.ceil().max(64.) as usize
+    } else {
+        64
+    }
+}
+
+impl Interpolate for View {
+    // the width changes exponentially, so a zoom looks like it goes at the same speed all the way.
+    // the centre moves in step with the width: the point of the plane that stays put on the screen
+    // is the same for the whole animation, so it looks like a zoom into that point instead of a pan
+    fn interpolate(&self, to: &View, t: f64) -> View {
+        if t == 0. {
+            return self.clone();
+        }
+        let tw = self.tw * (to.tw / self.tw).powf(t);
+        let progress = if (self.tw - to.tw).abs() > self.tw * 1e-12 {
+            (self.tw - tw) / (self.tw - to.tw)
+        } else {
+            // same width, a plain pan
+            t
+        };
+        let bits = interpolation_bits(self, to);
+        let corner = |from: &Coordinate, end: &Coordinate| {
+            interpolate_corner((from, self.tw), (end, to.tw), tw, progress, bits)
+        };
+        View {
+            tx: corner(&self.tx, &to.tx),
+            ty: corner(&self.ty, &to.ty),
+            tw,
+        }
+    }
+}
+
+// one coordinate of the corner of the view of width tw, with the centre moved by progress from the
+// centre of the first view to the one of the second. at the end of a deep zoom the corner moves by
+// less than the f64 grid from frame to frame, so it is worked out with the digits of both views
+fn interpolate_corner(
+    (from, from_tw): (&Coordinate, f64),
+    (to, to_tw): (&Coordinate, f64),
+    tw: f64,
+    progress: f64,
+    bits: usize,
+) -> Coordinate {
+    let half = |width: f64| BigFloat::from_f64(width / 2., bits);
+    let parse = |c: &Coordinate| precision::parse::<BigFloat>(&c.to_param(), bits);
+    let (start, end) = match (parse(from), parse(to)) {
+        (Some(start), Some(end)) => (start + half(from_tw), end + half(to_tw)),
+        // not a decimal number, e.g. inf, the validation of the frames turns it down
+        _ => {
+            let (start, end) = (from.value + from_tw / 2., to.value + to_tw / 2.);
+            return (start + (end - start) * progress - tw / 2.).into();
+        }
+    };
+    let corner = start.clone() + (end - start) * BigFloat::from_f64(progress, bits) - half(tw);
+    let value = corner.to_f64();
+    if (corner.clone() - BigFloat::from_f64(value, bits)).is_zero() {
+        value.into()
+    } else {
+        Coordinate {
+            value,
+            text: Some(corner.to_decimal()),
+        }
+    }
+}
+
+// how a point travels from one keyframe to the next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Path {
+    Linear,
+    // around the centre, with the distance to it changing linearly on the way.
+    // the shorter way around plus the given number of full turns, negative turns go clockwise
+    Circular { centre: Complex, turns: f64 },
+    // cubic bézier curve with two control points
+    Bezier { c1: Complex, c2: Complex },
+}
+
+impl Path {
+    pub fn point(&self, from: Complex, to: Complex, t: f64) -> Complex {
+        match self {
+            Path::Linear => from + (to - from) * t,
+            Path::Circular { centre, turns } => {
+                let (start, end) = (from - *centre, to - *centre);
+                let radius = start.abs() + (end.abs() - start.abs()) * t;
+                // the angle between the two points, between -pi and pi
+                let mut angle = end.arg() - start.arg();
+                if angle > PI {
+                    angle -= 2. * PI;
+                } else if angle <= -PI {
+                    angle += 2. * PI;
+                }
+                let angle = start.arg() + (angle + 2. * PI * turns) * t;
+                *centre + Complex { re: angle.cos(), im: angle.sin() } * radius
+            }
+            Path::Bezier { c1, c2 } => {
+                let u = 1. - t;
+                from * (u * u * u) + *c1 * (3. * u * u * t) + *c2 * (3. * u * t * t) + to * (t * t * t)
+            }
+        }
+    }
+}
+
+// what the points of a morph are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MorphKind {
+    // the coefficients, starting with the constant term
+    Coefficients,
+    Roots,
+}
+
+// the coefficients or the roots of the polynomial at a keyframe, and the paths they take
+// to get there from the previous keyframe, a single one for all of them or one per point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Morph {
+    pub points: Vec<Complex>,
+    pub paths: Vec<Path>,
+}
+
+impl Morph {
+    fn path(&self, i: usize) -> Path {
+        match self.paths.as_slice() {
+            [path] => *path,
+            paths => paths.get(i).copied().unwrap_or(Path::Linear),
+        }
+    }
+}
+
+impl Interpolate for Morph {
+    // a point missing on one side is zero, e.g. a coefficient that fades in
+    fn interpolate(&self, to: &Morph, t: f64) -> Morph {
+        let point = |points: &[Complex], i: usize| points.get(i).copied().unwrap_or(ZERO);
+        let len = self.points.len().max(to.points.len());
+        Morph {
+            points: (0..len)
+                .map(|i| to.path(i).point(point(&self.points, i), point(&to.points, i), t))
+                .collect(),
+            paths: to.paths.clone(),
+        }
+    }
+}
+
+// keeps track of which root is which while they move, so every basin keeps its colour.
+// the colour of a point only depends on the root it converged to, so the solutions
+// are moved onto the position their root had in the first frame
+#[derive(Default)]
+struct RootColours {
+    first: Vec<Complex>,
+    current: Vec<Complex>,
+}
+
+impl RootColours {
+    // the roots of the next frame, ordered if they are in the same order as the roots of the previous one
+    fn update(&mut self, roots: Vec<Complex>, ordered: bool) {
+        if roots.len() != self.first.len() {
+            // the degree changed, the roots can't be matched up anymore
+            self.first = roots.clone();
+        } else if !ordered {
+            self.current = matched(&self.current, &roots);
+            return;
+        }
+        self.current = roots;
+    }
+
+    fn recolour(&self, solutions: &mut [Solution], max_iter: u32) {
+        for solution in solutions.iter_mut().filter(|s| s.iter < max_iter) {
+            if let Some(i) = root_index(&self.current, &solution.root) {
+                solution.root = self.first[i];
+            }
+        }
+    }
+}
+
+// the roots in the order of the previous ones, the closest pairs are matched first
+fn matched(previous: &[Complex], roots: &[Complex]) -> Vec<Complex> {
+    let mut pairs: Vec<(f64, usize, usize)> = previous
+        .iter()
+        .enumerate()
+        .flat_map(|(i, p)| roots.iter().enumerate().map(move |(j, r)| ((*p - *r).abs(), i, j)))
+        .collect();
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut out = vec![None; previous.len()];
+    let mut taken = vec![false; roots.len()];
+    for (_, i, j) in pairs {
+        if out[i].is_none() && !taken[j] {
+            out[i] = Some(roots[j]);
+            taken[j] = true;
+        }
+    }
+    out.into_iter().map(Option::unwrap).collect()
+}
+
+// a render whose view and polynomial change from frame to frame
+pub struct AnimationRequest {
+    // the still render, its polynomial is used when nothing is morphed
+    pub request: RenderRequest,
+    pub frames: u32,
+    pub delay_ms: u32,
+    pub views: Timeline<View>,
+    pub morph: Option<(MorphKind, Timeline<Morph>)>,
+}
+
+impl AnimationRequest {
+    // the metadata of a frame written as an image of its own, the one of the request rendering
+    // that frame. a morphed polynomial has complex coefficients no request can hold, so those
+    // frames are only described
+    pub fn frame_metadata(&self, frame: u32) -> Vec<(String, String)> {
+        let view = self.views.at(frame);
+        let field = view.field(self.request.field.grid);
+        let request = RenderRequest {
+            exact_source: view.exact_source(&field),
+            field,
+            ..self.request.clone()
+        };
+        let mut metadata = request.metadata();
+        if let Some((kind, morph)) = &self.morph {
+            let points: Vec<String> = morph.at(frame).points.iter().map(|p| p.to_string()).collect();
+            let kind = match kind {
+                MorphKind::Coefficients => "coefficients",
+                MorphKind::Roots => "roots",
+            };
+            metadata.retain(|(key, _)| key != REQUEST_METADATA_KEY);
+            for (_, value) in metadata.iter_mut().filter(|(key, _)| key == "Description") {
+                *value = format!(
+                    "frame {} of a newton fractal animation, the polynomial has the {} [{}], \
+                     field starting at {} with size {}",
+                    frame,
+                    kind,
+                    points.join(", "),
+                    request.field.source,
+                    request.field.size,
+                );
+            }
+        }
+        metadata
+    }
+
+    // renders the frames in order and hands them to each, stopping at the first error.
+    // returns the iterations of all the frames together,
+    // none if cancel was set before the last frame was rendered
+    pub fn render_frames<E>(
+        &self,
+        cancel: &AtomicBool,
+        mut each: impl FnMut(u32, Rgb32FImage) -> Result<(), E>,
+    ) -> Option<Result<u64, E>> {
+        let grid = self.request.field.grid;
+        let max_iter = self.request.max_iter;
+        let mut colours = RootColours::default();
+        let mut iterations = 0;
+        for frame in 0..self.frames {
+            let view = self.views.at(frame);
+            let field = view.field(grid);
+            let exact = view.exact_source(&field);
+            let span = info_span!("solve", frame);
+            let solutions = match &self.morph {
+                None => span.in_scope(|| {
+                    newton_method_field_precise(&self.request.pol, &field, exact.as_ref(), max_iter, cancel)
+                })?,
+                Some((kind, morph)) => {
+                    let points = morph.at(frame).points;
+                    let (pol, roots, ordered) = match kind {
+                        MorphKind::Coefficients => {
+                            let pol = ComplexPolynomial::new(points);
+                            let roots = all_roots(&pol);
+                            (pol, roots, false)
+                        }
+                        MorphKind::Roots => (ComplexPolynomial::from_roots(&points), points, true),
+                    };
+                    colours.update(roots, ordered);
+                    let mut solutions =
+                        span.in_scope(|| newton_method_field_precise(&pol, &field, exact.as_ref(), max_iter, cancel))?;
+                    colours.recolour(&mut solutions, max_iter);
+                    solutions
+                }
+            };
+            iterations += solutions.iter().map(|s| s.iter as u64).sum::<u64>();
+            let image = info_span!("colour")
+                .in_scope(|| render_image_f32(&solutions, &field, max_iter, &self.request.options));
+            if let Err(e) = each(frame, image) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(iterations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn centre(view: &View) -> (f64, f64) {
+        (view.tx.value + view.tw / 2., view.ty.value + view.tw / 2.)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.)
+    }
+
+    #[test]
+    fn zoom_is_exponential() {
+        let from = View::new(-2., -2., 4.);
+        let to = View::new(0.5, 0.25, 0.0004);
+        let timeline = Timeline::between(from.clone(), to.clone(), 5);
+        assert_eq!(5, timeline.frames());
+        assert_eq!(from, timeline.at(0));
+        assert_eq!(to, timeline.at(4));
+        // the width shrinks by the same factor, 10, every frame
+        for frame in 0..4 {
+            assert!(close(timeline.at(frame).tw / 10., timeline.at(frame + 1).tw));
+        }
+    }
+
+    #[test]
+    fn zoom_has_a_fixed_point() {
+        let timeline = Timeline::between(View::new(-2., -2., 4.), View::new(0.5, 0.25, 0.5), 9);
+        // the point at the same relative position in the first and the last view
+        let relative =
+            |view: &View, (x, y): (f64, f64)| ((x - view.tx.value) / view.tw, (y - view.ty.value) / view.tw);
+        let (first, last) = (timeline.at(0), timeline.at(8));
+        let k = first.tw / (first.tw - last.tw);
+        let fixed = (
+            centre(&first).0 + (centre(&last).0 - centre(&first).0) * k,
+            centre(&first).1 + (centre(&last).1 - centre(&first).1) * k,
+        );
+        let expected = relative(&first, fixed);
+        for frame in 1..9 {
+            let position = relative(&timeline.at(frame), fixed);
+            assert!(close(expected.0, position.0) && close(expected.1, position.1), "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn deep_zoom_corners_keep_moving() {
+        let timeline = Timeline::between(View::new(-2., -2., 4.), View::new(-0.5, 0.25, 1e-24), 60);
+        let corner = |frame: u32| {
+            let view = timeline.at(frame);
+            let field = view.field(512);
+            let exact = view.exact_source(&field).expect("deep frames keep their digits");
+            let parse = |text: &str| precision::parse::<BigFloat>(text, 256).unwrap();
+            (parse(&exact.re), parse(&exact.im))
+        };
+        // the last frames move by less than the f64 grid around -0.5, but never stand still
+        for frame in 50..59 {
+            let (a, b) = (corner(frame), corner(frame + 1));
+            assert!(!(a.0 - b.0).is_zero() && !(a.1 - b.1).is_zero(), "frame {}", frame);
+        }
+        assert_eq!(View::new(-0.5, 0.25, 1e-24), timeline.at(59));
+    }
+
+    #[test]
+    fn holds_before_and_after_keyframes() {
+        let views = [View::new(0., 0., 1.), View::new(1., 1., 1.)];
+        let timeline = Timeline::new(vec![
+            Keyframe { frame: 8, value: views[1].clone() },
+            Keyframe { frame: 4, value: views[0].clone() },
+        ])
+        .unwrap();
+        assert_eq!(views[0], timeline.at(0));
+        assert_eq!(View::new(0.5, 0.5, 1.), timeline.at(6));
+        assert_eq!(views[1], timeline.at(20));
+        assert!(Timeline::<View>::new(vec![]).is_none());
+    }
+
+    #[test]
+    fn paths_end_at_the_keyframes() {
+        let (from, to) = (Complex { re: 1., im: 0. }, Complex { re: -1., im: 0.5 });
+        let paths = [
+            Path::Linear,
+            Path::Circular { centre: ZERO, turns: 1. },
+            Path::Bezier { c1: Complex { re: 3., im: 3. }, c2: ZERO },
+        ];
+        for path in paths {
+            assert_eq!(from, path.point(from, to, 0.));
+            assert_eq!(to, path.point(from, to, 1.));
+        }
+        // a full turn back to the start goes through the opposite point
+        let circle = Path::Circular { centre: ZERO, turns: 1. };
+        assert_eq!(Complex { re: -1., im: 0. }, circle.point(from, from, 0.5));
+        assert_eq!(Complex { re: 0., im: 1. }, circle.point(from, from, 0.25));
+    }
+
+    #[test]
+    fn roots_keep_their_identity() {
+        let a = Complex { re: 1., im: 0. };
+        let b = Complex { re: -1., im: 0. };
+        let mut colours = RootColours::default();
+        colours.update(vec![a, b], false);
+        // the roots moved a little and came back in the other order
+        let (a2, b2) = (a + Complex { re: 0.1, im: 0.1 }, b - Complex { re: 0.1, im: 0. });
+        colours.update(vec![b2, a2], false);
+        assert_eq!(vec![a2, b2], colours.current);
+
+        let mut solutions = vec![Solution { root: b2, iter: 5, smooth: 5. }];
+        colours.recolour(&mut solutions, 100);
+        assert_eq!(b, solutions[0].root);
+    }
+}
@@ -0,0 +1,357 @@
+use clap::Parser;
+use indicatif::ProgressBar;
+use newton_factal::animation::AnimationRequest;
+use newton_factal::encoding::{encode_image, AnimationEncoder, AnimationFormat};
+use newton_factal::math::precision::Precision;
+use newton_factal::request::{Defaults, Limits, Output, RenderRequest};
+use newton_factal::scene::{self, Animation, Coordinate, Function, Keyframe, Light, Scene, View};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::AtomicBool,
+    time::Instant,
+};
+
+// renders images to files, with the same parameters as GET / of the server or from scene files
+#[derive(Parser, Debug)]
+#[command(name = "render", version, about = "Render a newton fractal to a file")]
+struct Args {
+    /// Scene file (toml or json) describing the render, the other flags override it
+    #[arg(long, conflicts_with = "batch")]
+    scene: Option<PathBuf>,
+    /// Renders every .toml and .json scene in the directory, next to the scene unless --out-dir is given
+    #[arg(long)]
+    batch: Option<PathBuf>,
+    /// Directory the renders of --batch are written to
+    #[arg(long, requires = "batch")]
+    out_dir: Option<PathBuf>,
+    /// File the image is written to, its extension picks the format unless --format is given
+    /// [default: fractal.png, or the name of the scene]. For animations a .gif or .apng file,
+    /// or a directory the numbered frames are written to [default: frames, or the name of the scene .gif]
+    #[arg(short, long, conflicts_with = "batch")]
+    output: Option<PathBuf>,
+    /// Comma separated integer coefficients of the polynomial, starting with the constant term
+    #[arg(long, allow_hyphen_values = true, value_delimiter = ',', required_unless_present_any = ["scene", "batch"])]
+    pol: Option<Vec<i32>>,
+    /// Real part of the corner where the rendered area starts, deep zooms keep all its digits [default: -2]
+    #[arg(long, allow_hyphen_values = true)]
+    tx: Option<Coordinate>,
+    /// Imaginary part of the corner where the rendered area starts, deep zooms keep all its digits [default: -2]
+    #[arg(long, allow_hyphen_values = true)]
+    ty: Option<Coordinate>,
+    /// Width (and height) of the rendered area [default: 4]
+    #[arg(long)]
+    tw: Option<f64>,
+    /// Width (and height) of the image in pixels [default: 512]
+    #[arg(long)]
+    size: Option<u32>,
+    /// Maximum number of newton iterations per point [default: 100]
+    #[arg(long)]
+    max_iter: Option<u32>,
+    /// Shading: linear (default), smooth, histogram or histogram_root
+    #[arg(long)]
+    shading: Option<String>,
+    /// Light azimuth in degrees, renders the image with a 3D relief effect
+    #[arg(long, allow_hyphen_values = true)]
+    light_az: Option<f32>,
+    /// Light elevation in degrees [default: 45]
+    #[arg(long, allow_hyphen_values = true, requires = "light_az")]
+    light_el: Option<f32>,
+    /// Output format: png, jpeg, webp, tiff, bmp, qoi, exr, pfm, or npy, csv and bin for the raw data
+    #[arg(long)]
+    format: Option<String>,
+    /// Jpeg quality between 1 and 100 [default: 90]
+    #[arg(long)]
+    quality: Option<u8>,
+    /// Bits per channel: 8, 16 or 32
+    #[arg(long)]
+    depth: Option<u32>,
+    /// Renders a zoom animation of this many frames, from the view to --to, instead of the animation of the scene
+    #[arg(long, requires = "to", conflicts_with = "batch")]
+    frames: Option<u32>,
+    /// View the animation ends at, as tx,ty,tw
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true, value_names = ["TX,TY,TW"], requires = "frames")]
+    to: Option<Vec<f64>>,
+    /// Milliseconds every frame of a gif or apng animation is shown [default: 40]
+    #[arg(long)]
+    delay: Option<u32>,
+    /// Rendering threads, 0 for one per core
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+// nobody else is waiting for the machine, only the sanity checks of the server apply
+const NO_LIMITS: Limits = Limits {
+    max_grid: u32::MAX,
+    max_pixels: u64::MAX,
+    max_degree: u32::MAX as usize,
+    max_coefficients: usize::MAX,
+    max_iter: u32::MAX,
+    max_work: u64::MAX,
+    max_frames: u32::MAX,
+    min_delay_ms: 0,
+    max_animation_work: u64::MAX,
+    max_animation_pixels: u64::MAX,
+    max_body_bytes: usize::MAX,
+};
+
+fn main() {
+    let args = Args::parse();
+
+    if args.threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(args.threads)
+            .build_global()
+            .expect("rayon pool is only built once");
+    }
+
+    if let Some(dir) = &args.batch {
+        process::exit(batch(&args, dir));
+    }
+
+    let mut scene = match &args.scene {
+        Some(path) => Scene::from_file(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        }),
+        None => Scene {
+            function: Function { pol: vec![] },
+            view: View::new(-2.0, -2.0, 4.0),
+            solver: Default::default(),
+            colouring: Default::default(),
+            output: Default::default(),
+            size: None,
+            animation: None,
+        },
+    };
+    if let (Some(frames), Some(to)) = (args.frames, &args.to) {
+        let to = zoom_target(to).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+        scene.animation = Some(Animation {
+            frames,
+            delay: None,
+            keyframes: vec![Keyframe {
+                frame: frames.saturating_sub(1),
+                view: Some(to),
+                pol: None,
+                roots: None,
+                paths: vec![],
+            }],
+        });
+    }
+    // the format follows the file name, unless --format says otherwise.
+    // the output of an animation is an animation format or a directory instead
+    let extension_format = args.output.as_deref().and_then(|p| p.extension());
+    if let (Some(extension), None) = (extension_format, &scene.animation) {
+        scene.output.format = extension.to_str().map(|e| e.to_string());
+    }
+    apply(&args, &mut scene);
+
+    if scene.animation.is_some() {
+        let animation = scene.to_animation(&NO_LIMITS, &Defaults::default()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+        let output = args.output.clone().unwrap_or_else(|| match &args.scene {
+            Some(path) => PathBuf::from(path.file_stem().unwrap_or_default()).with_extension("gif"),
+            None => PathBuf::from("frames"),
+        });
+        if let Err(e) = animate(&animation, &output) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let request = scene.to_request(&NO_LIMITS, &Defaults::default()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    // without a file name the image is named after the scene
+    let output = args.output.clone().unwrap_or_else(|| {
+        let name = args.scene.as_deref().and_then(|p| p.file_stem()).unwrap_or("fractal".as_ref());
+        PathBuf::from(name).with_extension(extension(&request.output))
+    });
+    if let Err(e) = render(&request, &output) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+// renders every scene of the directory, a broken scene doesn't stop the others.
+// returns the exit code
+fn batch(args: &Args, dir: &Path) -> i32 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("can't read {}: {}", dir.display(), e);
+            return 2;
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && scene::is_scene_file(path))
+        .collect();
+    paths.sort();
+
+    let out_dir = args.out_dir.as_deref().unwrap_or(dir);
+    let mut failed = 0;
+    for path in &paths {
+        let result = Scene::from_file(path).map_err(|e| e.to_string()).and_then(|mut scene| {
+            apply(args, &mut scene);
+            let invalid = |e| format!("invalid scene {}: {}", path.display(), e);
+            let output = out_dir.join(path.file_stem().unwrap_or_default());
+            if scene.animation.is_some() {
+                let animation = scene.to_animation(&NO_LIMITS, &Defaults::default()).map_err(invalid)?;
+                return animate(&animation, &output.with_extension("gif"));
+            }
+            let request = scene.to_request(&NO_LIMITS, &Defaults::default()).map_err(invalid)?;
+            render(&request, &output.with_extension(extension(&request.output)))
+        });
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            failed += 1;
+        }
+    }
+    println!("{} of {} scenes rendered", paths.len() - failed, paths.len());
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+// the flags that were given replace the values of the scene
+fn apply(args: &Args, scene: &mut Scene) {
+    fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+        if let Some(value) = value {
+            *target = value.clone();
+        }
+    }
+    fn set_some<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+        if value.is_some() {
+            target.clone_from(value);
+        }
+    }
+
+    set(&mut scene.function.pol, &args.pol);
+    set(&mut scene.view.tx, &args.tx);
+    set(&mut scene.view.ty, &args.ty);
+    set(&mut scene.view.tw, &args.tw);
+    set_some(&mut scene.size, &args.size);
+    set_some(&mut scene.solver.max_iter, &args.max_iter);
+    set_some(&mut scene.colouring.shading, &args.shading);
+    if let Some(azimuth) = args.light_az {
+        scene.colouring.light = Some(Light {
+            azimuth,
+            elevation: args.light_el,
+        });
+    }
+    set_some(&mut scene.output.format, &args.format);
+    set_some(&mut scene.output.quality, &args.quality);
+    set_some(&mut scene.output.depth, &args.depth);
+    if let Some(animation) = &mut scene.animation {
+        set_some(&mut animation.delay, &args.delay);
+    }
+}
+
+fn render(request: &RenderRequest, output: &Path) -> Result<(), String> {
+    let started = Instant::now();
+    write_render(request, output)?;
+    let precision = match Precision::for_field(&request.field) {
+        Precision::Double => String::new(),
+        precision => format!(", {} precision", precision.name()),
+    };
+    println!(
+        "{} ({}x{}{}) in {:.2?}",
+        output.display(),
+        request.field.grid,
+        request.field.grid,
+        precision,
+        started.elapsed()
+    );
+    Ok(())
+}
+
+fn write_render(request: &RenderRequest, output: &Path) -> Result<(), String> {
+    let data = request.render().map_err(|e| format!("can't encode {}: {}", output.display(), e))?;
+    fs::write(output, data).map_err(|e| format!("can't write {}: {}", output.display(), e))
+}
+
+fn zoom_target(to: &[f64]) -> Result<View, String> {
+    match to {
+        [tx, ty, tw] => Ok(View::new(*tx, *ty, *tw)),
+        _ => Err("--to needs three values, tx,ty,tw".to_string()),
+    }
+}
+
+// writes the frames into a gif or apng, or as numbered images into a directory
+fn animate(animation: &AnimationRequest, output: &Path) -> Result<(), String> {
+    let grid = animation.request.field.grid;
+    let frames = animation.frames;
+    let format = output.extension().and_then(|e| e.to_str()).and_then(AnimationFormat::from_name);
+    let encode_error = |e| format!("can't encode {}: {}", output.display(), e);
+    let cancel = AtomicBool::new(false);
+    let started = Instant::now();
+    let progress = ProgressBar::new(frames as u64);
+    match format {
+        Some(format) => {
+            let file = File::create(output).map_err(|e| format!("can't write {}: {}", output.display(), e))?;
+            let mut encoder =
+                AnimationEncoder::new(BufWriter::new(file), format, (grid, grid), frames, animation.delay_ms)
+                    .map_err(encode_error)?;
+            animation
+                .render_frames(&cancel, |_, image| {
+                    progress.inc(1);
+                    encoder.add_frame(&image)
+                })
+                .expect("never cancelled")
+                .map_err(encode_error)?;
+            encoder.finish().map_err(encode_error)?;
+        }
+        // a file name that isn't an animation would become a directory full of frames
+        None if output.extension().is_some() && !output.is_dir() => {
+            return Err(format!(
+                "can't write an animation to {}, name it .gif or .apng, or give a directory for numbered frames",
+                output.display()
+            ));
+        }
+        None => {
+            let (format, depth) = match animation.request.output {
+                Output::Image { format, depth } => (format, depth),
+                Output::Data(_) => unreachable!("animations are validated to have images"),
+            };
+            fs::create_dir_all(output).map_err(|e| format!("can't create {}: {}", output.display(), e))?;
+            animation
+                .render_frames(&cancel, |frame, image| {
+                    progress.inc(1);
+                    let path = output.join(format!("frame_{:05}.{}", frame, format.name()));
+                    let metadata = animation.frame_metadata(frame);
+                    let data = encode_image(&image, &format, depth, &metadata).map_err(encode_error)?;
+                    fs::write(&path, data).map_err(|e| format!("can't write {}: {}", path.display(), e))
+                })
+                .expect("never cancelled")?;
+        }
+    }
+    progress.finish_and_clear();
+    println!(
+        "{} ({} frames of {}x{}) in {:.2?}",
+        output.display(),
+        frames,
+        grid,
+        grid,
+        started.elapsed()
+    );
+    Ok(())
+}
+
+fn extension(output: &Output) -> &'static str {
+    match output {
+        Output::Image { format, .. } => format.name(),
+        Output::Data(format) => format.name(),
+    }
+}
@@ -0,0 +1,318 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+
+use crate::request::RENDER_VERSION;
+
+// default size of the in memory cache
+pub const DEFAULT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+// a finished render, as it is sent to the client
+pub struct CachedRender {
+    pub data: Vec<u8>,
+    pub content_type: String,
+}
+
+// least recently used cache of finished renders, keyed by the canonical query of the request.
+// the cache is bounded by the total size of the renders, not by their number,
+// since a single 4k render can be as big as thousands of tiles.
+// if a directory is given, the renders are also written there and loaded back on start.
+pub struct RenderCache {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    dir: Option<Disk>,
+}
+
+// the files of a persistent cache are written and removed by their own thread,
+// in the order the cache asks for it, so the async executor never waits for the disk
+struct Disk {
+    path: PathBuf,
+    // none once the cache is dropped, which lets the thread finish
+    ops: Mutex<Option<Sender<DiskOp>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum DiskOp {
+    Write(PathBuf, String, Arc<CachedRender>),
+    Remove(PathBuf),
+}
+
+impl Disk {
+    fn new(path: &Path) -> Self {
+        let (send, recv) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            // persistence is best effort, the in memory cache works without it
+            for op in recv {
+                let _ = match op {
+                    DiskOp::Write(path, key, render) => write_entry(&path, &key, &render),
+                    DiskOp::Remove(path) => fs::remove_file(path),
+                };
+            }
+        });
+        Disk {
+            path: path.to_path_buf(),
+            ops: Mutex::new(Some(send)),
+            writer: Some(writer),
+        }
+    }
+
+    fn send(&self, op: DiskOp) {
+        if let Some(ops) = self.ops.lock().unwrap().as_ref() {
+            let _ = ops.send(op);
+        }
+    }
+}
+
+// waits for the pending writes, so a cache opened later on the same dir sees them
+impl Drop for Disk {
+    fn drop(&mut self) {
+        self.ops.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+struct Inner {
+    entries: LruCache<String, Arc<CachedRender>>,
+    size: usize,
+}
+
+impl Default for RenderCache {
+    fn default() -> Self {
+        RenderCache::new(DEFAULT_CACHE_BYTES)
+    }
+}
+
+impl RenderCache {
+    pub fn new(capacity: usize) -> Self {
+        RenderCache {
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                size: 0,
+            }),
+            capacity,
+            dir: None,
+        }
+    }
+
+    // cache that persists the renders in dir, renders already in the dir are loaded
+    pub fn with_dir(capacity: usize, dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut cache = RenderCache::new(capacity);
+        cache.dir = Some(Disk::new(dir));
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "render") {
+                // broken files, ones too big for the cache, ones rendered by another render version
+                // and ones that aren't named after the key they hold (e.g. from an older naming scheme)
+                // are just cache misses
+                match read_entry(&path) {
+                    Ok((key, render)) if render.data.len() <= capacity && path == entry_path(dir, &key) => {
+                        cache.put(key, Arc::new(render));
+                    }
+                    _ => {
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+            }
+        }
+
+        Ok(cache)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<CachedRender>> {
+        self.inner.lock().unwrap().entries.get(key).cloned()
+    }
+
+    // renders bigger than the whole cache are not stored
+    pub fn insert(&self, key: String, render: CachedRender) -> Arc<CachedRender> {
+        let render = Arc::new(render);
+        if render.data.len() > self.capacity {
+            return render;
+        }
+
+        if let Some(disk) = &self.dir {
+            disk.send(DiskOp::Write(entry_path(&disk.path, &key), key.clone(), render.clone()));
+        }
+
+        self.put(key, render.clone());
+        render
+    }
+
+    // stores the render in memory, evicting the least recently used ones to make space
+    fn put(&self, key: String, render: Arc<CachedRender>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.size += render.data.len();
+        if let Some(old) = inner.entries.put(key, render) {
+            inner.size -= old.data.len();
+        }
+        while inner.size > self.capacity {
+            match inner.entries.pop_lru() {
+                Some((key, evicted)) => {
+                    inner.size -= evicted.data.len();
+                    if let Some(disk) = &self.dir {
+                        disk.send(DiskOp::Remove(entry_path(&disk.path, &key)));
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    // total size of the cached renders in bytes
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+// strong etag of a render, renders are deterministic so the key identifies the content
+pub fn etag(key: &str) -> String {
+    format!("\"{}\"", digest(key))
+}
+
+// sha-256 of the key in hex. two keys sharing an etag or a file would serve the wrong
+// image (or evict each other's file), so the hash has to be collision resistant
+fn digest(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.render", digest(key)))
+}
+
+// the render version as u32 little endian, the key and the content type, each prefixed
+// by their length as u32 little endian, then the data
+fn write_entry(path: &Path, key: &str, render: &CachedRender) -> io::Result<()> {
+    let mut data = Vec::with_capacity(12 + key.len() + render.content_type.len() + render.data.len());
+    data.extend_from_slice(&RENDER_VERSION.to_le_bytes());
+    for part in [key.as_bytes(), render.content_type.as_bytes()] {
+        data.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        data.extend_from_slice(part);
+    }
+    data.extend_from_slice(&render.data);
+
+    // write and rename so a crash never leaves half a file behind
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+fn read_entry(path: &Path) -> io::Result<(String, CachedRender)> {
+    let data = fs::read(path)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "broken cache entry");
+
+    if data.get(..4) != Some(&RENDER_VERSION.to_le_bytes()[..]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "cache entry of another render version"));
+    }
+
+    let mut pos = 4;
+    let mut read_part = || {
+        let len_bytes = data.get(pos..pos + 4).ok_or_else(invalid)?;
+        let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+        let part = data.get(pos + 4..pos + 4 + len).ok_or_else(invalid)?;
+        pos += 4 + len;
+        String::from_utf8(part.to_vec()).map_err(|_| invalid())
+    };
+    let key = read_part()?;
+    let content_type = read_part()?;
+
+    Ok((
+        key,
+        CachedRender {
+            data: data[pos..].to_vec(),
+            content_type,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(size: usize) -> CachedRender {
+        CachedRender {
+            data: vec![0; size],
+            content_type: "image/png".to_string(),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = RenderCache::new(100);
+        cache.insert("a".to_string(), render(40));
+        cache.insert("b".to_string(), render(40));
+        cache.get("a");
+        cache.insert("c".to_string(), render(40));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert_eq!(80, cache.size());
+    }
+
+    #[test]
+    fn skips_renders_bigger_than_the_cache() {
+        let cache = RenderCache::new(100);
+        cache.insert("a".to_string(), render(101));
+        assert!(cache.get("a").is_none());
+        assert_eq!(0, cache.size());
+    }
+
+    #[test]
+    fn persists_between_restarts() {
+        let dir = std::env::temp_dir().join(format!("newton_factal_cache_{}", std::process::id()));
+        {
+            let cache = RenderCache::with_dir(100, &dir).unwrap();
+            cache.insert("pol=1,1".to_string(), render(10));
+        }
+        let cache = RenderCache::with_dir(100, &dir).unwrap();
+        let cached = cache.get("pol=1,1").unwrap();
+        assert_eq!(10, cached.data.len());
+        assert_eq!("image/png", cached.content_type);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn etag_is_stable() {
+        assert_eq!("\"ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb\"", etag("a"));
+    }
+
+    #[test]
+    fn entries_are_checked_against_their_key() {
+        let dir = std::env::temp_dir().join(format!("newton_factal_cache_key_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // a file holding the render of "a" where the one of "b" belongs
+        let wrong = entry_path(&dir, "b");
+        write_entry(&wrong, "a", &render(10)).unwrap();
+        let cache = RenderCache::with_dir(100, &dir).unwrap();
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_none());
+        assert!(!wrong.exists());
+        drop(cache);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn entries_of_other_render_versions_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("newton_factal_cache_version_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = entry_path(&dir, "a");
+        write_entry(&path, "a", &render(10)).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[..4].copy_from_slice(&(RENDER_VERSION - 1).to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let cache = RenderCache::with_dir(100, &dir).unwrap();
+        assert!(cache.get("a").is_none());
+        assert!(!path.exists());
+        drop(cache);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
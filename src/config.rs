@@ -0,0 +1,347 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+use crate::cache::DEFAULT_CACHE_BYTES;
+use crate::request::{Defaults, Limits};
+
+// renders already use every rayon thread, running more than a few at once
+// only makes each of them slower
+pub const DEFAULT_MAX_CONCURRENT_RENDERS: usize = 4;
+// renders waiting for a slot, past this new ones get 429
+pub const DEFAULT_MAX_QUEUED_RENDERS: usize = 256;
+// a map view on a full hd screen asks for up to 9x6 tiles at once, and the viewer for some more
+// around them, more than that waiting from a single client is too much
+pub const DEFAULT_MAX_QUEUED_PER_CLIENT: usize = 64;
+pub const DEFAULT_RENDER_TIMEOUT_SECS: u64 = 120;
+// animations may take ten times the work of a render (see Limits), and so ten times as long
+pub const DEFAULT_ANIMATION_TIMEOUT_SECS: u64 = 1200;
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+
+// settings of the server. each value comes from, in order of precedence,
+// the command line, the NEWTON_* environment variables, the config file and the defaults
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    // number of rayon threads rendering the images, 0 for one per core
+    pub threads: usize,
+    pub max_concurrent_renders: usize,
+    pub max_queued_renders: usize,
+    pub max_queued_per_client: usize,
+    // seconds a render may take before it is cancelled, 0 for no deadline
+    pub render_timeout: u64,
+    // same for animations, which take many renders
+    pub animation_timeout: u64,
+    // seconds active requests get to finish on shutdown
+    pub shutdown_grace: u64,
+    pub defaults: Defaults,
+    pub limits: Limits,
+    pub cache: CacheConfig,
+    // tracing filter, e.g. "info" or "newton_factal=debug,hyper=info"
+    pub log: String,
+    pub log_format: LogFormat,
+}
+
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // human readable lines
+    #[default]
+    Text,
+    // one json object per line, for log collectors
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // total size of the cached renders in bytes
+    pub max_bytes: usize,
+    // if set, the cache is also kept in this directory and survives restarts
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3000,
+            threads: 0,
+            max_concurrent_renders: DEFAULT_MAX_CONCURRENT_RENDERS,
+            max_queued_renders: DEFAULT_MAX_QUEUED_RENDERS,
+            max_queued_per_client: DEFAULT_MAX_QUEUED_PER_CLIENT,
+            render_timeout: DEFAULT_RENDER_TIMEOUT_SECS,
+            animation_timeout: DEFAULT_ANIMATION_TIMEOUT_SECS,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE_SECS,
+            defaults: Defaults::default(),
+            limits: Limits::default(),
+            cache: CacheConfig::default(),
+            log: "info".to_string(),
+            log_format: LogFormat::default(),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: DEFAULT_CACHE_BYTES,
+            dir: None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("can't read config file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid config file {}: {source}", path.display())]
+    Toml { path: PathBuf, source: toml::de::Error },
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
+
+// command line of the server, every flag can also be set with an environment variable
+#[derive(Parser, Debug, Default)]
+#[command(about = "Newton fractal server")]
+pub struct Args {
+    /// Toml config file
+    #[arg(short, long, env = "NEWTON_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, env = "NEWTON_BIND")]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on
+    #[arg(short, long, env = "NEWTON_PORT")]
+    pub port: Option<u16>,
+    /// Rendering threads, 0 for one per core
+    #[arg(long, env = "NEWTON_THREADS")]
+    pub threads: Option<usize>,
+    /// Maximum number of renders running at the same time
+    #[arg(long, env = "NEWTON_MAX_CONCURRENT_RENDERS")]
+    pub max_concurrent_renders: Option<usize>,
+    /// Maximum number of renders waiting for a slot
+    #[arg(long, env = "NEWTON_MAX_QUEUED_RENDERS")]
+    pub max_queued_renders: Option<usize>,
+    /// Maximum number of renders waiting for a slot from a single client
+    #[arg(long, env = "NEWTON_MAX_QUEUED_PER_CLIENT")]
+    pub max_queued_per_client: Option<usize>,
+    /// Seconds a render may take before it is cancelled, 0 for no deadline
+    #[arg(long, env = "NEWTON_RENDER_TIMEOUT")]
+    pub render_timeout: Option<u64>,
+    /// Seconds an animation may take before it is cancelled, 0 for no deadline
+    #[arg(long, env = "NEWTON_ANIMATION_TIMEOUT")]
+    pub animation_timeout: Option<u64>,
+    /// Seconds active requests get to finish on shutdown
+    #[arg(long, env = "NEWTON_SHUTDOWN_GRACE")]
+    pub shutdown_grace: Option<u64>,
+    /// Image size used when a request doesn't set one
+    #[arg(long, env = "NEWTON_DEFAULT_GRID")]
+    pub default_grid: Option<u32>,
+    /// Iterations used when a request doesn't set them
+    #[arg(long, env = "NEWTON_DEFAULT_MAX_ITER")]
+    pub default_max_iter: Option<u32>,
+    /// Largest image size a request may ask for
+    #[arg(long, env = "NEWTON_MAX_GRID")]
+    pub max_grid: Option<u32>,
+    /// Most pixels an image may have
+    #[arg(long, env = "NEWTON_MAX_PIXELS")]
+    pub max_pixels: Option<u64>,
+    /// Highest polynomial degree a request may ask for
+    #[arg(long, env = "NEWTON_MAX_DEGREE")]
+    pub max_degree: Option<usize>,
+    /// Most coefficients a polynomial may have, trailing zeros included
+    #[arg(long, env = "NEWTON_MAX_COEFFICIENTS")]
+    pub max_coefficients: Option<usize>,
+    /// Most iterations a request may ask for
+    #[arg(long, env = "NEWTON_MAX_ITER")]
+    pub max_iter: Option<u32>,
+    /// Most coefficient evaluations a single render may take
+    #[arg(long, env = "NEWTON_MAX_WORK")]
+    pub max_work: Option<u64>,
+    /// Most frames an animation may have
+    #[arg(long, env = "NEWTON_MAX_FRAMES")]
+    pub max_frames: Option<u32>,
+    /// Shortest time in milliseconds a frame of an animation may be shown
+    #[arg(long, env = "NEWTON_MIN_DELAY_MS")]
+    pub min_delay_ms: Option<u32>,
+    /// Most coefficient evaluations all the frames of an animation may take together
+    #[arg(long, env = "NEWTON_MAX_ANIMATION_WORK")]
+    pub max_animation_work: Option<u64>,
+    /// Most pixels all the frames of an animation may have together
+    #[arg(long, env = "NEWTON_MAX_ANIMATION_PIXELS")]
+    pub max_animation_pixels: Option<u64>,
+    /// Largest request body in bytes, bigger scenes and images are rejected
+    #[arg(long, env = "NEWTON_MAX_BODY_BYTES")]
+    pub max_body_bytes: Option<usize>,
+    /// Size of the render cache in bytes
+    #[arg(long, env = "NEWTON_CACHE_BYTES")]
+    pub cache_bytes: Option<usize>,
+    /// Directory keeping the render cache between restarts
+    #[arg(long, env = "NEWTON_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Log filter, e.g. "info" or "newton_factal=debug"
+    #[arg(long, env = "NEWTON_LOG")]
+    pub log: Option<String>,
+    /// Log output format
+    #[arg(long, env = "NEWTON_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Config {
+    // the config described by the command line and environment,
+    // on top of the config file they point to, if any
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Toml {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    // overrides the values set in args
+    fn apply(&mut self, args: &Args) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        set(&mut self.bind, &args.bind);
+        set(&mut self.port, &args.port);
+        set(&mut self.threads, &args.threads);
+        set(&mut self.max_concurrent_renders, &args.max_concurrent_renders);
+        set(&mut self.max_queued_renders, &args.max_queued_renders);
+        set(&mut self.max_queued_per_client, &args.max_queued_per_client);
+        set(&mut self.render_timeout, &args.render_timeout);
+        set(&mut self.animation_timeout, &args.animation_timeout);
+        set(&mut self.shutdown_grace, &args.shutdown_grace);
+        set(&mut self.defaults.grid, &args.default_grid);
+        set(&mut self.defaults.max_iter, &args.default_max_iter);
+        set(&mut self.limits.max_grid, &args.max_grid);
+        set(&mut self.limits.max_pixels, &args.max_pixels);
+        set(&mut self.limits.max_degree, &args.max_degree);
+        set(&mut self.limits.max_coefficients, &args.max_coefficients);
+        set(&mut self.limits.max_iter, &args.max_iter);
+        set(&mut self.limits.max_work, &args.max_work);
+        set(&mut self.limits.max_frames, &args.max_frames);
+        set(&mut self.limits.min_delay_ms, &args.min_delay_ms);
+        set(&mut self.limits.max_animation_work, &args.max_animation_work);
+        set(&mut self.limits.max_animation_pixels, &args.max_animation_pixels);
+        set(&mut self.limits.max_body_bytes, &args.max_body_bytes);
+        set(&mut self.cache.max_bytes, &args.cache_bytes);
+        set(&mut self.log, &args.log);
+        set(&mut self.log_format, &args.log_format);
+        if args.cache_dir.is_some() {
+            self.cache.dir = args.cache_dir.clone();
+        }
+    }
+
+    // catches settings that would make every request fail
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+        if self.max_concurrent_renders == 0 {
+            return invalid("max_concurrent_renders must be greater than 0".to_string());
+        }
+        if self.defaults.grid == 0 || self.defaults.grid > self.limits.max_grid {
+            return invalid(format!("default grid must be between 1 and {}", self.limits.max_grid));
+        }
+        if self.defaults.grid as u64 * self.defaults.grid as u64 > self.limits.max_pixels {
+            return invalid(format!("default grid can't have more than {} pixels", self.limits.max_pixels));
+        }
+        if self.defaults.max_iter == 0 || self.defaults.max_iter > self.limits.max_iter {
+            return invalid(format!("default max_iter must be between 1 and {}", self.limits.max_iter));
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log) {
+            return invalid(format!("log filter {:?}: {}", self.log, e));
+        }
+        Ok(())
+    }
+
+    pub fn render_timeout(&self) -> Option<Duration> {
+        Some(self.render_timeout)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn animation_timeout(&self) -> Option<Duration> {
+        Some(self.animation_timeout)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_values_with_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            port = 8080
+            [limits]
+            max_grid = 1024
+            [cache]
+            dir = "/tmp/renders"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(8080, config.port);
+        assert_eq!(1024, config.limits.max_grid);
+        assert_eq!(Limits::default().max_iter, config.limits.max_iter);
+        assert_eq!(Some(PathBuf::from("/tmp/renders")), config.cache.dir);
+        assert_eq!(DEFAULT_CACHE_BYTES, config.cache.max_bytes);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("prot = 8080").is_err());
+    }
+
+    #[test]
+    fn args_override_the_file() {
+        let mut config: Config = toml::from_str("port = 8080\nthreads = 2").unwrap();
+        let args = Args::try_parse_from(["newton_factal", "--port", "9000", "--default-grid", "256"]).unwrap();
+        config.apply(&args);
+        assert_eq!(9000, config.port);
+        assert_eq!(2, config.threads);
+        assert_eq!(256, config.defaults.grid);
+    }
+
+    #[test]
+    fn defaults_must_fit_the_limits() {
+        let mut config = Config::default();
+        config.defaults.max_iter = config.limits.max_iter + 1;
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use std::io::{Cursor, Write};
+
+use image::error::{
+    DecodingError, EncodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind,
+};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{
+    Delay, DynamicImage, Frame, ImageError, ImageFormat, ImageOutputFormat, ImageResult, Rgb32FImage,
+};
+
+// the jpeg quality used when none is requested
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+// formats a rendered image can be encoded into.
+// png is the default since fractals have a lot of sharp edges,
+// and lossy formats tend to smear them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    // quality between 1 and 100
+    Jpeg(u8),
+    // lossless webp
+    WebP,
+    Tiff,
+    Bmp,
+    Qoi,
+    // openexr, always 32 bit float
+    Exr,
+    // portable float map, always 32 bit float
+    Pfm,
+}
+
+// how many bits are used for every color channel in the encoded image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+    Float,
+}
+
+impl BitDepth {
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            8 => Some(BitDepth::Eight),
+            16 => Some(BitDepth::Sixteen),
+            32 => Some(BitDepth::Float),
+            _ => None,
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        match self {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+            BitDepth::Float => 32,
+        }
+    }
+}
+
+impl OutputFormat {
+    // all the supported formats, in the order of preference
+    // used when the client doesn't care about the format
+    pub const ALL: [OutputFormat; 8] = [
+        OutputFormat::Png,
+        OutputFormat::WebP,
+        OutputFormat::Jpeg(DEFAULT_JPEG_QUALITY),
+        OutputFormat::Tiff,
+        OutputFormat::Bmp,
+        OutputFormat::Qoi,
+        OutputFormat::Exr,
+        OutputFormat::Pfm,
+    ];
+
+    // format from its short name, as used in the format query parameter and file extensions
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg(DEFAULT_JPEG_QUALITY)),
+            "webp" => Some(OutputFormat::WebP),
+            "tiff" | "tif" => Some(OutputFormat::Tiff),
+            "bmp" => Some(OutputFormat::Bmp),
+            "qoi" => Some(OutputFormat::Qoi),
+            "exr" => Some(OutputFormat::Exr),
+            "pfm" => Some(OutputFormat::Pfm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg(_) => "jpeg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Qoi => "qoi",
+            OutputFormat::Exr => "exr",
+            OutputFormat::Pfm => "pfm",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg(_) => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Tiff => "image/tiff",
+            OutputFormat::Bmp => "image/bmp",
+            // there is no registered mime type for qoi yet
+            OutputFormat::Qoi => "image/x-qoi",
+            OutputFormat::Exr => "image/x-exr",
+            OutputFormat::Pfm => "image/x-portable-floatmap",
+        }
+    }
+
+    // the bit depth used when none is requested
+    pub fn default_depth(&self) -> BitDepth {
+        match self {
+            OutputFormat::Exr | OutputFormat::Pfm => BitDepth::Float,
+            _ => BitDepth::Eight,
+        }
+    }
+
+    pub fn supports(&self, depth: BitDepth) -> bool {
+        match self {
+            OutputFormat::Png | OutputFormat::Tiff => depth != BitDepth::Float,
+            OutputFormat::Exr | OutputFormat::Pfm => depth == BitDepth::Float,
+            _ => depth == BitDepth::Eight,
+        }
+    }
+}
+
+// encodes the rendered image, the channels are quantized to the requested depth here.
+// metadata is a list of (key, value) text pairs, it is written as tEXt chunks
+// for png and as comment segments for jpeg, other formats don't store it
+pub fn encode_image(
+    image: &Rgb32FImage,
+    format: &OutputFormat,
+    depth: BitDepth,
+    metadata: &[(String, String)],
+) -> ImageResult<Vec<u8>> {
+    if !format.supports(depth) {
+        return Err(ImageError::Unsupported(
+            UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Name(format.name().to_string()),
+                UnsupportedErrorKind::GenericFeature(format!("{} bit depth", depth.bits())),
+            ),
+        ));
+    }
+
+    if *format == OutputFormat::Pfm {
+        return Ok(encode_pfm(image));
+    }
+
+    let output_format = match format {
+        OutputFormat::Png => ImageOutputFormat::Png,
+        OutputFormat::Jpeg(quality) => ImageOutputFormat::Jpeg(*quality),
+        OutputFormat::WebP => ImageOutputFormat::WebP,
+        OutputFormat::Tiff => ImageOutputFormat::Tiff,
+        OutputFormat::Bmp => ImageOutputFormat::Bmp,
+        OutputFormat::Qoi => ImageOutputFormat::Qoi,
+        OutputFormat::Exr => ImageOutputFormat::OpenExr,
+        OutputFormat::Pfm => unreachable!("pfm is encoded by hand"),
+    };
+
+    let image = DynamicImage::ImageRgb32F(image.clone());
+    let image = match depth {
+        BitDepth::Eight => DynamicImage::ImageRgb8(image.into_rgb8()),
+        BitDepth::Sixteen => DynamicImage::ImageRgb16(image.into_rgb16()),
+        BitDepth::Float => image,
+    };
+
+    if *format == OutputFormat::Png {
+        return encode_png(&image, metadata);
+    }
+
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, output_format)?;
+    let data = data.into_inner();
+
+    match format {
+        OutputFormat::Jpeg(_) => add_jpeg_comments(data, metadata),
+        _ => Ok(data),
+    }
+}
+
+// reads the text metadata written by encode_image, images
+// in formats that can't carry metadata have none
+pub fn read_metadata(data: &[u8]) -> ImageResult<Vec<(String, String)>> {
+    match image::guess_format(data)? {
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(data)
+                .read_info()
+                .map_err(|e| ImageError::Decoding(DecodingError::new(ImageFormat::Png.into(), e)))?;
+            Ok(reader
+                .info()
+                .uncompressed_latin1_text
+                .iter()
+                .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+                .collect())
+        }
+        ImageFormat::Jpeg => Ok(read_jpeg_comments(data)),
+        _ => Ok(vec![]),
+    }
+}
+
+// the image crate has no way to add text chunks, so png goes through the png crate directly
+fn encode_png(image: &DynamicImage, metadata: &[(String, String)]) -> ImageResult<Vec<u8>> {
+    let to_image_error = |e| ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), e));
+
+    let (depth, bytes) = match image {
+        DynamicImage::ImageRgb8(image) => (png::BitDepth::Eight, image.as_raw().clone()),
+        // png stores 16 bit samples as big endian
+        DynamicImage::ImageRgb16(image) => (
+            png::BitDepth::Sixteen,
+            image.as_raw().iter().flat_map(|c| c.to_be_bytes()).collect(),
+        ),
+        _ => unreachable!("png is only encoded with 8 or 16 bits"),
+    };
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(depth);
+    for (key, value) in metadata {
+        encoder
+            .add_text_chunk(key.clone(), value.clone())
+            .map_err(to_image_error)?;
+    }
+    let mut writer = encoder.write_header().map_err(to_image_error)?;
+    writer.write_image_data(&bytes).map_err(to_image_error)?;
+    writer.finish().map_err(to_image_error)?;
+    Ok(data)
+}
+
+// formats an animation can be encoded into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnimationFormat {
+    // 256 colours per frame, but every browser and chat app plays it
+    Gif,
+    // animated png, full colour
+    Apng,
+}
+
+impl AnimationFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gif" => Some(AnimationFormat::Gif),
+            "apng" => Some(AnimationFormat::Apng),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Apng => "apng",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "image/gif",
+            AnimationFormat::Apng => "image/apng",
+        }
+    }
+}
+
+// writes an animation frame by frame, so the frames don't have to be kept in memory.
+// the animation loops forever, every frame is shown for the same delay
+pub struct AnimationEncoder<W: Write> {
+    inner: AnimationWriter<W>,
+    delay_ms: u32,
+}
+
+enum AnimationWriter<W: Write> {
+    Gif(GifEncoder<W>),
+    Apng(png::Writer<W>),
+}
+
+impl<W: Write> AnimationEncoder<W> {
+    pub fn new(
+        out: W,
+        format: AnimationFormat,
+        size: (u32, u32),
+        frames: u32,
+        delay_ms: u32,
+    ) -> ImageResult<Self> {
+        let inner = match format {
+            AnimationFormat::Gif => {
+                // the default speed of 1 takes seconds to quantize a single large frame
+                let mut encoder = GifEncoder::new_with_speed(out, 10);
+                encoder.set_repeat(Repeat::Infinite)?;
+                AnimationWriter::Gif(encoder)
+            }
+            AnimationFormat::Apng => {
+                let mut encoder = png::Encoder::new(out, size.0, size.1);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frames, 0).map_err(png_error)?;
+                // apng delays are a fraction of a second with a 16 bit numerator,
+                // long ones are rounded to hundredths like in gifs
+                let (numerator, denominator) = match u16::try_from(delay_ms) {
+                    Ok(ms) => (ms, 1000),
+                    Err(_) => ((delay_ms / 10).min(u16::MAX as u32) as u16, 100),
+                };
+                encoder.set_frame_delay(numerator, denominator).map_err(png_error)?;
+                AnimationWriter::Apng(encoder.write_header().map_err(png_error)?)
+            }
+        };
+        Ok(AnimationEncoder { inner, delay_ms })
+    }
+
+    pub fn add_frame(&mut self, image: &Rgb32FImage) -> ImageResult<()> {
+        let image = DynamicImage::ImageRgb32F(image.clone());
+        match &mut self.inner {
+            AnimationWriter::Gif(encoder) => {
+                let delay = Delay::from_numer_denom_ms(self.delay_ms, 1);
+                encoder.encode_frame(Frame::from_parts(image.into_rgba8(), 0, 0, delay))
+            }
+            AnimationWriter::Apng(writer) => writer.write_image_data(image.into_rgb8().as_raw()).map_err(png_error),
+        }
+    }
+
+    // fails if fewer frames than announced were added
+    pub fn finish(self) -> ImageResult<()> {
+        match self.inner {
+            // the gif trailer is written when the encoder is dropped
+            AnimationWriter::Gif(_) => Ok(()),
+            AnimationWriter::Apng(writer) => writer.finish().map_err(png_error),
+        }
+    }
+}
+
+fn png_error(e: png::EncodingError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), e))
+}
+
+const JPEG_COMMENT: u8 = 0xFE;
+const JPEG_START_OF_SCAN: u8 = 0xDA;
+const JPEG_APP0: u8 = 0xE0;
+
+// every (key, value) pair becomes a "key: value" comment segment, they go after the
+// APP0 (JFIF) segment of the encoder because JFIF requires it to follow the start of image marker
+fn add_jpeg_comments(data: Vec<u8>, metadata: &[(String, String)]) -> ImageResult<Vec<u8>> {
+    let mut insert_at = 2;
+    if data.len() >= 6 && data[2] == 0xFF && data[3] == JPEG_APP0 {
+        insert_at = usize::min(4 + u16::from_be_bytes([data[4], data[5]]) as usize, data.len());
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..insert_at]);
+    for (key, value) in metadata {
+        let comment = format!("{}: {}", key, value);
+        // the segment length includes the two length bytes and can't be more than u16::MAX
+        if comment.len() > u16::MAX as usize - 2 {
+            return Err(ImageError::Encoding(EncodingError::new(
+                ImageFormat::Jpeg.into(),
+                format!("the {} metadata is too long for a jpeg comment", key),
+            )));
+        }
+        out.extend_from_slice(&[0xFF, JPEG_COMMENT]);
+        out.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(comment.as_bytes());
+    }
+    out.extend_from_slice(&data[insert_at..]);
+    Ok(out)
+}
+
+// walks the jpeg segments until the image data starts and collects the "key: value" comments
+fn read_jpeg_comments(data: &[u8]) -> Vec<(String, String)> {
+    let mut comments = Vec::new();
+    // skip the start of image marker
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF && data[pos + 1] != JPEG_START_OF_SCAN {
+        let marker = data[pos + 1];
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = usize::min(pos + 2 + len, data.len());
+        if marker == JPEG_COMMENT && len >= 2 {
+            let comment = String::from_utf8_lossy(&data[pos + 4..end]);
+            if let Some((key, value)) = comment.split_once(": ") {
+                comments.push((key.to_string(), value.to_string()));
+            }
+        }
+        pos = end;
+    }
+    comments
+}
+
+// the image crate can't write pfm, but the format is simple enough:
+// a text header followed by little endian floats, rows stored bottom to top
+fn encode_pfm(image: &Rgb32FImage) -> Vec<u8> {
+    let mut data = Vec::new();
+    // a negative scale marks little endian data
+    write!(data, "PF\n{} {}\n-1.0\n", image.width(), image.height()).unwrap();
+    for row in image.rows().rev() {
+        for pixel in row {
+            for c in pixel.0 {
+                data.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_all_formats() {
+        let image = Rgb32FImage::new(8, 8);
+        for format in OutputFormat::ALL {
+            let data = encode_image(&image, &format, format.default_depth(), &[]).unwrap();
+            let expected = image::ImageFormat::from_mime_type(format.content_type());
+            if let Some(expected) = expected {
+                assert_eq!(expected, image::guess_format(&data).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn encode_animations() {
+        let frames = [Rgb32FImage::new(4, 4), Rgb32FImage::from_pixel(4, 4, image::Rgb([1.0, 0.5, 0.0]))];
+        for format in [AnimationFormat::Gif, AnimationFormat::Apng] {
+            let mut data = Vec::new();
+            let mut encoder = AnimationEncoder::new(&mut data, format, (4, 4), 2, 100).unwrap();
+            for frame in &frames {
+                encoder.add_frame(frame).unwrap();
+            }
+            encoder.finish().unwrap();
+
+            let decoder = png::Decoder::new(data.as_slice()).read_info();
+            match format {
+                AnimationFormat::Gif => assert_eq!(ImageFormat::Gif, image::guess_format(&data).unwrap()),
+                AnimationFormat::Apng => assert_eq!(2, decoder.unwrap().info().animation_control.unwrap().num_frames),
+            }
+        }
+    }
+
+    #[test]
+    fn encode_png_16_bit() {
+        let image = Rgb32FImage::from_pixel(4, 4, image::Rgb([0.5, 0.25, 1.0]));
+        let data = encode_image(&image, &OutputFormat::Png, BitDepth::Sixteen, &[]).unwrap();
+        let decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!(image::ColorType::Rgb16, decoded.color());
+    }
+
+    #[test]
+    fn encode_pfm_header() {
+        let image = Rgb32FImage::new(3, 2);
+        let data = encode_image(&image, &OutputFormat::Pfm, BitDepth::Float, &[]).unwrap();
+        assert!(data.starts_with(b"PF\n3 2\n-1.0\n"));
+        assert_eq!(b"PF\n3 2\n-1.0\n".len() + 3 * 2 * 3 * 4, data.len());
+    }
+
+    #[test]
+    fn unsupported_depth() {
+        let image = Rgb32FImage::new(2, 2);
+        assert!(encode_image(&image, &OutputFormat::Jpeg(90), BitDepth::Sixteen, &[]).is_err());
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let image = Rgb32FImage::new(4, 4);
+        let metadata = vec![
+            ("Software".to_string(), "test".to_string()),
+            ("Render request".to_string(), "pol=-1,0,1&tx=-1".to_string()),
+        ];
+        for format in [OutputFormat::Png, OutputFormat::Jpeg(90)] {
+            let data = encode_image(&image, &format, BitDepth::Eight, &metadata).unwrap();
+            assert_eq!(metadata, read_metadata(&data).unwrap());
+            // the image is still readable
+            image::load_from_memory(&data).unwrap();
+        }
+    }
+
+    #[test]
+    fn jpeg_comments_follow_jfif_header() {
+        let image = Rgb32FImage::new(4, 4);
+        let metadata = vec![("Software".to_string(), "test".to_string())];
+        let data = encode_image(&image, &OutputFormat::Jpeg(90), BitDepth::Eight, &metadata).unwrap();
+        assert_eq!([0xFF, 0xD8, 0xFF, JPEG_APP0], data[..4]);
+        assert_eq!(&b"JFIF\0"[..], &data[6..11]);
+
+        let metadata = vec![("Render request".to_string(), "x".repeat(u16::MAX as usize))];
+        assert!(encode_image(&image, &OutputFormat::Jpeg(90), BitDepth::Eight, &metadata).is_err());
+    }
+}
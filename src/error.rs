@@ -0,0 +1,88 @@
+use std::time::Duration;
+use thiserror::Error;
+
+use ServerError::ParsingError;
+
+// errors of parsing, validating and rendering a request, the server turns them into
+// json responses (see server::error_json) and the render binary prints them
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("passing error on field: {field:?}, message: {message:?}")]
+    ParsingError {
+        field: String,
+        message: String,
+    },
+    // the value could be parsed, but it doesn't make sense
+    #[error("invalid value of field: {field:?}, message: {message:?}")]
+    ValidationError {
+        field: String,
+        message: String,
+    },
+    // the request is valid, but asks for more than the server allows
+    #[error("limit exceeded on field: {field:?}, message: {message:?}")]
+    LimitExceeded {
+        field: String,
+        message: String,
+    },
+    #[error("not found")]
+    NotFound,
+    // the body of the request is bigger than the server reads
+    #[error("request body is larger than {limit} bytes")]
+    PayloadTooLarge { limit: usize },
+    #[error("unable to encode image: {0}")]
+    EncodingError(String),
+    #[error("render failed: {0}")]
+    RenderError(String),
+    // the render didn't finish before the deadline of the request
+    #[error("render took longer than {0:?}")]
+    Timeout(Duration),
+    // too many renders are already running or waiting
+    #[error("too many renders in progress, retry in {retry_after:?}")]
+    Overloaded { retry_after: Duration },
+    #[error("unknown error")]
+    UnknownError,
+}
+
+impl ServerError {
+    // machine readable name of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::InvalidArgument(_) => "invalid_argument",
+            ParsingError { .. } => "parsing_error",
+            ServerError::ValidationError { .. } => "validation_error",
+            ServerError::LimitExceeded { .. } => "limit_exceeded",
+            ServerError::NotFound => "not_found",
+            ServerError::PayloadTooLarge { .. } => "payload_too_large",
+            ServerError::EncodingError(_) => "encoding_error",
+            ServerError::RenderError(_) => "render_error",
+            ServerError::Timeout(_) => "timeout",
+            ServerError::Overloaded { .. } => "overloaded",
+            ServerError::UnknownError => "unknown_error",
+        }
+    }
+
+    // the field of the request the error is about, if any
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            ParsingError { field, .. }
+            | ServerError::ValidationError { field, .. }
+            | ServerError::LimitExceeded { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+
+    // the error without the field, which is reported separately
+    pub fn message(&self) -> String {
+        match self {
+            ServerError::InvalidArgument(message)
+            | ParsingError { message, .. }
+            | ServerError::ValidationError { message, .. }
+            | ServerError::LimitExceeded { message, .. }
+            | ServerError::EncodingError(message)
+            | ServerError::RenderError(message) => message.clone(),
+            e => e.to_string(),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::Write;
+
+use crate::{distinct_roots, root_index, Field, Solution};
+
+// formats for exporting the raw solutions of a field instead of a picture.
+// every point has the index of the root it converged to (-1 if it didn't converge),
+// the iteration count, the smooth iteration count and the final value of z.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataFormat {
+    // numpy structured array of shape (grid, grid),
+    // the first axis is the real part, the second the imaginary part
+    Npy,
+    // one line per point, with the grid coordinates of the point
+    Csv,
+    // small header followed by fixed size little endian records, see export_binary
+    Binary,
+}
+
+impl DataFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "npy" => Some(DataFormat::Npy),
+            "csv" => Some(DataFormat::Csv),
+            "bin" => Some(DataFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataFormat::Npy => "npy",
+            DataFormat::Csv => "csv",
+            DataFormat::Binary => "bin",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            DataFormat::Npy | DataFormat::Binary => "application/octet-stream",
+            DataFormat::Csv => "text/csv",
+        }
+    }
+}
+
+// magic bytes at the start of the binary export
+pub const BINARY_MAGIC: &[u8; 6] = b"NFRAW\0";
+pub const BINARY_VERSION: u16 = 1;
+
+// the solutions are expected in the same order as returned by newton_method_field
+pub fn export_solutions(
+    solutions: &[Solution],
+    field: &Field,
+    max_iter: u32,
+    format: &DataFormat,
+) -> Vec<u8> {
+    let grid = field.grid as usize;
+    assert_eq!(grid * grid, solutions.len(), "not enough values in solutions");
+
+    let roots = distinct_roots(solutions, max_iter);
+    let root_of = |s: &Solution| {
+        if s.iter < max_iter {
+            root_index(&roots, &s.root).map_or(-1, |i| i as i32)
+        } else {
+            -1
+        }
+    };
+
+    match format {
+        DataFormat::Npy => export_npy(solutions, field, root_of),
+        DataFormat::Csv => export_csv(solutions, field, root_of),
+        DataFormat::Binary => export_binary(solutions, field, max_iter, root_of),
+    }
+}
+
+// https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html
+fn export_npy(solutions: &[Solution], field: &Field, root_of: impl Fn(&Solution) -> i32) -> Vec<u8> {
+    let mut header = format!(
+        "{{'descr': [('root', '<i4'), ('iter', '<u4'), ('smooth', '<f8'), ('re', '<f8'), ('im', '<f8')], \
+         'fortran_order': False, 'shape': ({}, {}), }}",
+        field.grid, field.grid
+    );
+    // magic (6) + version (2) + header length (2) + header has to be aligned to 64 bytes
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut data = Vec::with_capacity(10 + header.len() + solutions.len() * 32);
+    data.extend_from_slice(b"\x93NUMPY\x01\x00");
+    data.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    data.extend_from_slice(header.as_bytes());
+    for s in solutions {
+        write_record(&mut data, s, root_of(s));
+    }
+    data
+}
+
+fn export_csv(solutions: &[Solution], field: &Field, root_of: impl Fn(&Solution) -> i32) -> Vec<u8> {
+    let mut data = Vec::new();
+    writeln!(data, "i,j,root,iter,smooth,re,im").unwrap();
+    for (index, s) in solutions.iter().enumerate() {
+        let i = index / field.grid as usize;
+        let j = index % field.grid as usize;
+        writeln!(
+            data,
+            "{},{},{},{},{},{},{}",
+            i,
+            j,
+            root_of(s),
+            s.iter,
+            s.smooth,
+            s.root.re,
+            s.root.im
+        )
+        .unwrap();
+    }
+    data
+}
+
+// layout of the binary export, everything is little endian:
+// - magic "NFRAW\0" (6 bytes) and format version (u16)
+// - grid (u32), max_iter (u32), field source re and im (f64) and field size (f64)
+// - grid * grid records of root (i32), iter (u32), smooth (f64), re (f64), im (f64)
+fn export_binary(
+    solutions: &[Solution],
+    field: &Field,
+    max_iter: u32,
+    root_of: impl Fn(&Solution) -> i32,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(40 + solutions.len() * 32);
+    data.extend_from_slice(BINARY_MAGIC);
+    data.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+    data.extend_from_slice(&field.grid.to_le_bytes());
+    data.extend_from_slice(&max_iter.to_le_bytes());
+    data.extend_from_slice(&field.source.re.to_le_bytes());
+    data.extend_from_slice(&field.source.im.to_le_bytes());
+    data.extend_from_slice(&field.size.to_le_bytes());
+    for s in solutions {
+        write_record(&mut data, s, root_of(s));
+    }
+    data
+}
+
+fn write_record(data: &mut Vec<u8>, s: &Solution, root: i32) {
+    data.extend_from_slice(&root.to_le_bytes());
+    data.extend_from_slice(&s.iter.to_le_bytes());
+    data.extend_from_slice(&s.smooth.to_le_bytes());
+    data.extend_from_slice(&s.root.re.to_le_bytes());
+    data.extend_from_slice(&s.root.im.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::complex::Complex;
+
+    fn solutions() -> (Vec<Solution>, Field) {
+        let field = Field {
+            source: Complex { re: -1., im: -1. },
+            size: 2.,
+            grid: 2,
+        };
+        let root = |re| Complex { re, im: 0. };
+        let solutions = vec![
+            Solution { root: root(1.), iter: 3, smooth: 2.5 },
+            Solution { root: root(-1.), iter: 4, smooth: 3.5 },
+            Solution { root: root(1.), iter: 5, smooth: 4.5 },
+            Solution { root: root(7.), iter: 10, smooth: 10. },
+        ];
+        (solutions, field)
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let (solutions, field) = solutions();
+        let data = export_solutions(&solutions, &field, 10, &DataFormat::Npy);
+        let header_len = u16::from_le_bytes([data[8], data[9]]) as usize;
+        assert_eq!(0, (10 + header_len) % 64);
+        assert_eq!(b'\n', data[10 + header_len - 1]);
+        assert_eq!(10 + header_len + 4 * 32, data.len());
+    }
+
+    #[test]
+    fn csv_root_indexes() {
+        let (solutions, field) = solutions();
+        let data = export_solutions(&solutions, &field, 10, &DataFormat::Csv);
+        let csv = String::from_utf8(data).unwrap();
+        let roots: Vec<&str> = csv
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').nth(2).unwrap())
+            .collect();
+        assert_eq!(vec!["0", "1", "0", "-1"], roots);
+    }
+
+    #[test]
+    fn binary_header() {
+        let (solutions, field) = solutions();
+        let data = export_solutions(&solutions, &field, 10, &DataFormat::Binary);
+        assert!(data.starts_with(BINARY_MAGIC));
+        assert_eq!(40 + 4 * 32, data.len());
+    }
+}
@@ -1,12 +1,27 @@
+pub mod analysis;
+pub mod animation;
+pub mod cache;
+pub mod config;
+pub mod encoding;
+pub mod error;
+pub mod export;
 pub mod math;
+pub mod metrics;
+pub mod queue;
 pub mod rendering;
+pub mod request;
+pub mod scene;
 pub mod server;
+pub mod stream;
+
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use itertools::Itertools;
 use rayon::prelude::*;
 
 use math::complex::Complex;
-use math::polynomial::Polynomial;
+use math::polynomial::{ComplexPolynomial, Differentiable};
+use math::precision::{self, BigFloat, DoubleDouble, ExactPoint, Precision, PreciseComplex, QuadDouble, Real};
 
 // retpresentation of a complex plane area starting at source and growing by size in both dimensions
 // the grid defines how to turn the continuous plane into descrete values
@@ -14,35 +29,125 @@ use math::polynomial::Polynomial;
 // the area is always a square for simplicity, size defines the side of the square
 // grid is for one dimension as well, so a field with size 10 and grid 4 will be of area 10*10 = 100
 // and, when discretized, will have 4*4=16 points.
+#[derive(Debug, Clone, Copy)]
 pub struct Field {
     pub source: Complex,
     pub size: f64,
     pub grid: u32,
 }
+#[derive(Clone, Copy)]
 pub struct Solution {
     pub root: Complex,
     pub iter: u32,
+    // continuous version of iter, it lies between iter - 1 and iter and
+    // it is used when the rendering needs a smooth "height" instead of discrete bands
+    pub smooth: f64,
 }
 
 // find root points for all the values in the field
 // todo clean up the abstractoin here: we path a 2d field,
 // but the return type is a vector it is just an assumption
 // that the 1d solutions vector is mapped back to the 2d field
-pub fn newton_method_field(pol: &Polynomial, field: &Field, max_iter: u32) -> Vec<Solution> {
+pub fn newton_method_field<P: Differentiable>(pol: &P, field: &Field, max_iter: u32) -> Vec<Solution> {
+    newton_method_field_cancellable(pol, field, max_iter, &AtomicBool::new(false))
+        .expect("never cancelled")
+}
+
+// same as newton_method_field, but gives up and returns none as soon as cancel is set.
+// the flag is checked for every point, so a cancelled render stops within a few points per thread
+pub fn newton_method_field_cancellable<P: Differentiable>(
+    pol: &P,
+    field: &Field,
+    max_iter: u32,
+    cancel: &AtomicBool,
+) -> Option<Vec<Solution>> {
     let dpol = pol.derivative();
 
     field
         .values()
         .par_iter()
-        .map(|point| newton_method_approximate(&pol, &dpol, point, max_iter))
+        .map(|point| {
+            if cancel.load(Ordering::Relaxed) {
+                None
+            } else {
+                Some(newton_method_approximate(pol, &dpol, point, max_iter))
+            }
+        })
         .collect()
 }
 
+// coarsest pass of the progressive solver has at least this many points per side
+const MIN_PASS_GRID: u32 = 32;
+
+// solves the field in passes, from a coarse grid up to the full one, calling pass after every
+// pass but the last with the coarse field and its solutions. each pass only solves the points
+// the coarser passes didn't, so getting the previews costs next to nothing on top of the full solve.
+// a pass with stride s solves the points whose indices are both multiples of s,
+// the coarse field of that pass is those points.
+pub fn newton_method_field_progressive<P: Differentiable>(
+    pol: &P,
+    field: &Field,
+    max_iter: u32,
+    cancel: &AtomicBool,
+    mut pass: impl FnMut(&Field, &[Solution]),
+) -> Option<Vec<Solution>> {
+    let dpol = pol.derivative();
+    let grid = field.grid as usize;
+    let step = field.size / (field.grid as f64);
+    let mut solved: Vec<Option<Solution>> = vec![None; grid * grid];
+
+    let mut stride = 1;
+    while field.grid / (stride * 2) >= MIN_PASS_GRID {
+        stride *= 2;
+    }
+
+    loop {
+        // same order as Field::values, the real part is the outer loop
+        let indices = |stride: usize| {
+            (0..grid)
+                .step_by(stride)
+                .flat_map(move |i| (0..grid).step_by(stride).map(move |j| i * grid + j))
+        };
+        let todo: Vec<usize> = indices(stride as usize).filter(|&k| solved[k].is_none()).collect();
+        let new: Vec<(usize, Solution)> = todo
+            .par_iter()
+            .map(|&k| {
+                if cancel.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let point = Complex {
+                    re: field.source.re + ((k / grid) as f64) * step,
+                    im: field.source.im + ((k % grid) as f64) * step,
+                };
+                Some((k, newton_method_approximate(pol, &dpol, &point, max_iter)))
+            })
+            .collect::<Option<_>>()?;
+        for (k, solution) in new {
+            solved[k] = Some(solution);
+        }
+
+        if stride == 1 {
+            break;
+        }
+        let coarse_grid = field.grid.div_ceil(stride);
+        let coarse = Field {
+            source: field.source,
+            size: step * (stride * coarse_grid) as f64,
+            grid: coarse_grid,
+        };
+        let solutions: Vec<Solution> = indices(stride as usize).map(|k| solved[k].unwrap()).collect();
+        pass(&coarse, &solutions);
+        stride /= 2;
+    }
+
+    Some(solved.into_iter().map(Option::unwrap).collect())
+}
+
 // find the root point of the fractal using the newton's approximation method for one starting point
 // this method is the most resource heavy part of the code
-pub fn newton_method_approximate(
-    pol: &Polynomial,
-    dpol: &Polynomial,
+pub fn newton_method_approximate<P: Differentiable>(
+    pol: &P,
+    dpol: &P,
     point: &Complex,
     max_iter: u32,
 ) -> Solution {
@@ -50,6 +155,7 @@ pub fn newton_method_approximate(
 
     let mut iter = 0;
     let mut diff = 10.0;
+    let mut prev_diff = diff;
     let mut guess = point.clone();
 
     while iter < max_iter && diff > tolerance {
@@ -60,12 +166,149 @@ pub fn newton_method_approximate(
         let next = guess - div;
         let dist = distance(next, guess);
         guess = next;
+        prev_diff = diff;
         diff = dist;
 
         iter += 1;
     }
 
-    Solution { root: guess, iter }
+    let smooth = smooth_iter(iter, prev_diff, diff, tolerance);
+    Solution { root: guess, iter, smooth }
+}
+
+// newton_method_field_cancellable at the precision the field needs, see math::precision.
+// exact_source is the corner of the field with the digits field.source can't hold, if any
+pub fn newton_method_field_precise<P: Differentiable>(
+    pol: &P,
+    field: &Field,
+    exact_source: Option<&ExactPoint>,
+    max_iter: u32,
+    cancel: &AtomicBool,
+) -> Option<Vec<Solution>> {
+    fn solve<R: Real>(
+        pol: &ComplexPolynomial,
+        field: &Field,
+        exact_source: Option<&ExactPoint>,
+        bits: usize,
+        max_iter: u32,
+        cancel: &AtomicBool,
+    ) -> Option<Vec<Solution>> {
+        let source = exact_source
+            .and_then(|exact| exact.to_precise(bits))
+            .unwrap_or_else(|| PreciseComplex::from_complex(&field.source, bits));
+        newton_method_field_with::<R>(pol, field, &source, bits, max_iter, cancel)
+    }
+
+    let precision = Precision::for_field(field);
+    let bits = precision.bits();
+    match precision {
+        Precision::Double => newton_method_field_cancellable(pol, field, max_iter, cancel),
+        Precision::DoubleDouble => solve::<DoubleDouble>(&pol.to_complex(), field, exact_source, bits, max_iter, cancel),
+        Precision::QuadDouble => solve::<QuadDouble>(&pol.to_complex(), field, exact_source, bits, max_iter, cancel),
+        Precision::Arbitrary(_) => solve::<BigFloat>(&pol.to_complex(), field, exact_source, bits, max_iter, cancel),
+    }
+}
+
+// newton_method_field_cancellable with the points and the iterations in R, the field starts at source
+// instead of field.source. the coefficients of the polynomial are rounded to f64 anyway
+pub fn newton_method_field_with<R: Real>(
+    pol: &ComplexPolynomial,
+    field: &Field,
+    source: &PreciseComplex<R>,
+    bits: usize,
+    max_iter: u32,
+    cancel: &AtomicBool,
+) -> Option<Vec<Solution>> {
+    let precise = |pol: &ComplexPolynomial| -> Vec<PreciseComplex<R>> {
+        pol.coefficients().iter().map(|c| PreciseComplex::from_complex(c, bits)).collect()
+    };
+    let (coeff, dcoeff) = (precise(pol), precise(&pol.derivative()));
+    let step = R::from_f64(field.size, bits) / R::from_f64(field.grid as f64, bits);
+    let grid = field.grid as usize;
+
+    (0..grid * grid)
+        .into_par_iter()
+        .map(|k| {
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            // same order as Field::values, the real part is the outer loop
+            let offset = |i: usize| R::from_f64(i as f64, bits) * step.clone();
+            let point = PreciseComplex {
+                re: source.re.clone() + offset(k / grid),
+                im: source.im.clone() + offset(k % grid),
+            };
+            Some(newton_method_approximate_with(&coeff, &dcoeff, point, max_iter))
+        })
+        .collect()
+}
+
+// newton_method_approximate in R, pol and dpol are the coefficients lowest first
+pub fn newton_method_approximate_with<R: Real>(
+    pol: &[PreciseComplex<R>],
+    dpol: &[PreciseComplex<R>],
+    point: PreciseComplex<R>,
+    max_iter: u32,
+) -> Solution {
+    let tolerance = f64::powi(10.0, -6);
+
+    let mut iter = 0;
+    let mut diff = 10.0;
+    let mut prev_diff = diff;
+    let mut guess = point;
+
+    while iter < max_iter && diff > tolerance {
+        let val = precision::evaluate(pol, &guess);
+        let der = precision::evaluate(dpol, &guess);
+        iter += 1;
+        // a critical point. f64 ends up with nan here, BigFloat would panic
+        if der.norm_sqr().is_zero() {
+            let nan = Complex { re: f64::NAN, im: f64::NAN };
+            return Solution { root: nan, iter, smooth: iter as f64 };
+        }
+        let div = val / der;
+
+        // the step is the distance between the guesses, it is small enough for f64
+        let step = div.to_complex();
+        guess = guess - div;
+        prev_diff = diff;
+        diff = step.re.powi(2) + step.im.powi(2);
+    }
+
+    let smooth = smooth_iter(iter, prev_diff, diff, tolerance);
+    Solution { root: guess.to_complex(), iter, smooth }
+}
+
+// newton's method converges quadratically close to a root, so the log of the
+// step size roughly doubles every iteration. Using the step before the last one
+// we can estimate at which fractional iteration the step crossed the tolerance.
+// Points that did not converge (or converged in the first step) keep the integer count.
+fn smooth_iter(iter: u32, prev_diff: f64, diff: f64, tolerance: f64) -> f64 {
+    if diff > tolerance || prev_diff >= 1.0 || prev_diff <= tolerance {
+        return iter as f64;
+    }
+    let frac = (tolerance.ln() / prev_diff.ln()).log2();
+    (iter - 1) as f64 + frac.clamp(0.0, 1.0)
+}
+
+// the distinct roots the solutions converged to, in the order they were first found.
+// points that did not converge within max_iter are ignored since their "root"
+// is just wherever the iteration happened to stop, and so are the nan roots of
+// points that hit a critical point, nan never equals itself so each would count as a new root.
+pub fn distinct_roots(solutions: &[Solution], max_iter: u32) -> Vec<Complex> {
+    let mut roots: Vec<Complex> = Vec::new();
+    let converged = |s: &&Solution| s.iter < max_iter && s.root.re.is_finite() && s.root.im.is_finite();
+    for solution in solutions.iter().filter(converged) {
+        if !roots.contains(&solution.root) {
+            roots.push(solution.root);
+        }
+    }
+    roots
+}
+
+// index of the root the point converged to, none if it is not one of the roots
+pub fn root_index(roots: &[Complex], point: &Complex) -> Option<usize> {
+    roots.iter().position(|r| r == point)
 }
 
 // euqlidean distance without sqrt.
@@ -91,3 +334,71 @@ impl Field {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::polynomial::Polynomial;
+
+    #[test]
+    fn smooth_iter_is_between_iterations() {
+        // y = x^3 - 1
+        let pol = Polynomial::new(vec![-1, 0, 0, 1]);
+        let dpol = pol.derivative();
+        let point = Complex { re: 0.7, im: 0.4 };
+        let solution = newton_method_approximate(&pol, &dpol, &point, 100);
+        assert!(solution.smooth <= solution.iter as f64);
+        assert!(solution.smooth >= (solution.iter - 1) as f64);
+    }
+
+    #[test]
+    fn smooth_iter_not_converged() {
+        let pol = Polynomial::new(vec![-1, 0, 0, 1]);
+        let dpol = pol.derivative();
+        let point = Complex { re: 0.7, im: 0.4 };
+        let solution = newton_method_approximate(&pol, &dpol, &point, 2);
+        assert_eq!(2, solution.iter);
+        assert_eq!(2.0, solution.smooth);
+    }
+
+    #[test]
+    fn distinct_roots_skip_critical_points() {
+        // the grid puts points right on the critical point at 0, they end up as nan
+        let pol = Polynomial::new(vec![-1, 0, 0, 1]);
+        let field = Field { source: Complex { re: -1.0, im: -1.0 }, size: 2.0, grid: 100 };
+        let solutions = newton_method_field(&pol, &field, 50);
+        assert!(solutions.iter().any(|s| s.root.re.is_nan() && s.iter < 50));
+        let roots = distinct_roots(&solutions, 50);
+        assert_eq!(3, roots.len());
+        assert!(roots.iter().all(|r| r.re.is_finite() && r.im.is_finite()));
+    }
+
+    #[test]
+    fn progressive_matches_field() {
+        let pol = Polynomial::new(vec![-1, 0, 0, 1]);
+        let field = Field { source: Complex { re: -1.0, im: -1.0 }, size: 2.0, grid: 100 };
+        let mut passes = Vec::new();
+        let progressive = newton_method_field_progressive(&pol, &field, 20, &AtomicBool::new(false), |coarse, s| {
+            assert_eq!((coarse.grid * coarse.grid) as usize, s.len());
+            passes.push(coarse.grid);
+        })
+        .unwrap();
+        assert_eq!(vec![50], passes);
+
+        let direct = newton_method_field(&pol, &field, 20);
+        // bitwise, the point at 0 ends up as nan
+        let same = |p: &Solution, d: &Solution| {
+            p.iter == d.iter && p.root.re.to_bits() == d.root.re.to_bits() && p.root.im.to_bits() == d.root.im.to_bits()
+        };
+        assert!(progressive.iter().zip(&direct).all(|(p, d)| same(p, d)));
+    }
+
+    #[test]
+    fn cancelled_field() {
+        let pol = Polynomial::new(vec![-1, 0, 0, 1]);
+        let field = Field { source: Complex { re: -1.0, im: -1.0 }, size: 2.0, grid: 16 };
+        assert!(newton_method_field_cancellable(&pol, &field, 10, &AtomicBool::new(true)).is_none());
+        let solutions = newton_method_field_cancellable(&pol, &field, 10, &AtomicBool::new(false));
+        assert_eq!(256, solutions.unwrap().len());
+    }
+}
@@ -1,22 +1,120 @@
+use clap::Parser;
 use hyper::{
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Server,
+    Body, Request, Server,
 };
-use newton_factal::server::api;
-use std::{convert::Infallible, net::SocketAddr};
+use newton_factal::config::{Args, Config, LogFormat};
+use newton_factal::server::{api, ServerContext};
+use std::{convert::Infallible, process, sync::Arc};
+use tokio::{signal, sync::Notify};
+use tracing::{error, info, warn};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 #[tokio::main]
 async fn main() {
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let config = Config::load(&Args::parse()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    init_logging(&config);
+
+    if config.threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .build_global()
+            .expect("rayon pool is only built once");
+    }
 
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, Infallible>(service_fn(api))
+    let ctx = match ServerContext::from_config(&config) {
+        Ok(ctx) => Arc::new(ctx),
+        Err(e) => {
+            error!(error = %e, "can't open the render cache");
+            process::exit(1);
+        }
+    };
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let ctx = ctx.clone();
+        let remote = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                // the queue tells clients apart by their address
+                req.extensions_mut().insert(remote);
+                api(ctx.clone(), req)
+            }))
+        }
     });
 
-    let server = Server::bind(&addr).serve(make_svc);
+    let addr = config.addr();
+    let shutdown = Arc::new(Notify::new());
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_svc).with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown_signal().await;
+                info!("shutting down, waiting for active requests");
+                shutdown.notify_one();
+            }
+        }),
+        Err(e) => {
+            error!(error = %e, %addr, "can't listen");
+            process::exit(1);
+        }
+    };
+    info!(%addr, "listening");
+
+    // after the signal the server stops accepting connections and finishes when the
+    // active requests are done, renders still running after the grace period are abandoned
+    let grace = config.shutdown_grace();
+    tokio::select! {
+        result = server => {
+            if let Err(e) = result {
+                error!(error = %e, "server error");
+            }
+        }
+        _ = async {
+            shutdown.notified().await;
+            tokio::time::sleep(grace).await;
+        } => {
+            warn!(?grace, "active requests didn't finish in time, exiting");
+        }
+    }
+}
+
+// spans are logged when they close, with the time spent in them,
+// so the logs show how long parsing, solving and encoding took
+fn init_logging(config: &Config) {
+    // the filter was checked when the config was loaded
+    let filter = EnvFilter::new(&config.log);
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    match config.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+}
+
+// ctrl+c or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        signal::ctrl_c().await.expect("can't listen for ctrl+c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("can't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    // Run this server for... forever!
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
     }
 }
@@ -1,2 +1,4 @@
 pub mod complex;
 pub mod polynomial;
+pub mod precision;
+pub mod roots;
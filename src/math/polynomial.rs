@@ -3,7 +3,7 @@ use std::fmt;
 
 // represents a polynomial
 // the i-th coefficient is the coefficiet in front of x^i
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Polynomial {
     coeff: Vec<i32>,
 }
@@ -13,6 +13,16 @@ impl Polynomial {
         Polynomial { coeff }
     }
 
+    // the coefficients, the i-th one is the coefficient in front of x^i
+    pub fn coefficients(&self) -> &[i32] {
+        &self.coeff
+    }
+
+    // the highest power with a non zero coefficient, none for the zero polynomial
+    pub fn degree(&self) -> Option<usize> {
+        self.coeff.iter().rposition(|c| *c != 0)
+    }
+
     // derivates coefficient
     pub fn derivative(&self) -> Polynomial {
         if self.coeff.len() <= 1 {
@@ -46,8 +56,8 @@ impl Polynomial {
         acc
     }
 
-    // todo do I need this method?
-    fn show_part(index: usize, coef: i32) -> String {
+    // a term without its sign, the sign goes in between the terms
+    fn show_part(index: usize, coef: u32) -> String {
         let s_coef = if coef == 1 {
             String::from("")
         } else {
@@ -61,18 +71,110 @@ impl Polynomial {
     }
 }
 
-impl fmt::Display for Polynomial {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let res = self
+// what newton's method needs to know about the function it finds the roots of
+pub trait Differentiable: Sync {
+    fn evaluate(&self, z: &Complex) -> Complex;
+    fn derivative(&self) -> Self;
+    // the coefficients for the solvers that don't work with f64, see math::precision
+    fn to_complex(&self) -> ComplexPolynomial;
+}
+
+impl Differentiable for Polynomial {
+    fn evaluate(&self, z: &Complex) -> Complex {
+        Polynomial::evaluate(self, z)
+    }
+
+    fn derivative(&self) -> Self {
+        Polynomial::derivative(self)
+    }
+
+    fn to_complex(&self) -> ComplexPolynomial {
+        ComplexPolynomial::from(self)
+    }
+}
+
+// polynomial with complex coefficients, e.g. the frames of an animation morphing
+// one polynomial into another, or moving its roots around
+#[derive(Debug, Clone)]
+pub struct ComplexPolynomial {
+    coeff: Vec<Complex>,
+}
+
+impl ComplexPolynomial {
+    pub fn new(coeff: Vec<Complex>) -> Self {
+        ComplexPolynomial { coeff }
+    }
+
+    // the monic polynomial with exactly these roots, (x - r0)(x - r1)...
+    pub fn from_roots(roots: &[Complex]) -> Self {
+        let mut coeff = vec![Complex { re: 1., im: 0. }];
+        for root in roots {
+            // multiply by (x - root)
+            let mut next = vec![ZERO; coeff.len() + 1];
+            for (i, c) in coeff.iter().enumerate() {
+                next[i + 1] = next[i + 1] + *c;
+                next[i] = next[i] - *c * *root;
+            }
+            coeff = next;
+        }
+        ComplexPolynomial { coeff }
+    }
+
+    pub fn coefficients(&self) -> &[Complex] {
+        &self.coeff
+    }
+
+    // the highest power with a non zero coefficient, none for the zero polynomial
+    pub fn degree(&self) -> Option<usize> {
+        self.coeff.iter().rposition(|c| c.re != 0. || c.im != 0.)
+    }
+}
+
+impl Differentiable for ComplexPolynomial {
+    // horner's method
+    fn evaluate(&self, z: &Complex) -> Complex {
+        self.coeff.iter().rev().fold(ZERO, |acc, c| acc * *z + *c)
+    }
+
+    fn derivative(&self) -> Self {
+        let coeff = self
             .coeff
             .iter()
             .enumerate()
-            .filter(|(_, coef)| **coef > 0)
-            .map(|(index, coef)| Polynomial::show_part(index, *coef))
-            .reduce(|a, b| a + " + " + &b)
-            .unwrap_or_else(|| String::from(""));
+            .skip(1)
+            .map(|(index, coef)| *coef * (index as f64))
+            .collect();
+        ComplexPolynomial { coeff }
+    }
+
+    fn to_complex(&self) -> ComplexPolynomial {
+        self.clone()
+    }
+}
 
-        write!(f, "{}", res)
+impl From<&Polynomial> for ComplexPolynomial {
+    fn from(pol: &Polynomial) -> Self {
+        let coeff = pol.coeff.iter().map(|c| Complex { re: *c as f64, im: 0. }).collect();
+        ComplexPolynomial { coeff }
+    }
+}
+
+// lowest power first, e.g. "-1 + x^3" or "1 - 2x", the zero polynomial is "0"
+impl fmt::Display for Polynomial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut terms = self.coeff.iter().enumerate().filter(|(_, coef)| **coef != 0);
+        match terms.next() {
+            Some((index, coef)) => {
+                let sign = if *coef < 0 { "-" } else { "" };
+                write!(f, "{}{}", sign, Polynomial::show_part(index, coef.unsigned_abs()))?;
+            }
+            None => return write!(f, "0"),
+        }
+        for (index, coef) in terms {
+            let sign = if *coef < 0 { "-" } else { "+" };
+            write!(f, " {} {}", sign, Polynomial::show_part(index, coef.unsigned_abs()))?;
+        }
+        Ok(())
     }
 }
 
@@ -112,6 +214,18 @@ mod tests {
         assert_eq!(vec![1, 4, 9, 20, 40], pol.derivative().coeff);
     }
 
+    #[test]
+    fn degree_ignores_trailing_zeros() {
+        let pol = Polynomial::new(vec![1, 0, 3, 0, 0]);
+        assert_eq!(Some(2), pol.degree());
+    }
+
+    #[test]
+    fn degree_zero_polynomial() {
+        assert_eq!(None, Polynomial::new(vec![0, 0]).degree());
+        assert_eq!(None, Polynomial::new(vec![]).degree());
+    }
+
     #[test]
     fn display_constant() {
         // y = 1
@@ -140,6 +254,32 @@ mod tests {
         assert_eq!("1 + x + 2x^2 + 3x^3 + 5x^4 + 8x^5", format!("{}", pol));
     }
 
+    #[test]
+    fn display_negative() {
+        assert_eq!("-1 + x^3", format!("{}", Polynomial::new(vec![-1, 0, 0, 1])));
+        assert_eq!("1 - 2x - x^2", format!("{}", Polynomial::new(vec![1, -2, -1])));
+        assert_eq!("0", format!("{}", Polynomial::new(vec![0])));
+    }
+
+    #[test]
+    fn complex_from_roots() {
+        // (x - i)(x + i) = 1 + x^2
+        let i = Complex { re: 0., im: 1. };
+        let pol = ComplexPolynomial::from_roots(&[i, ZERO - i]);
+        let expected = ComplexPolynomial::from(&Polynomial::new(vec![1, 0, 1]));
+        assert_eq!(expected.coefficients(), pol.coefficients());
+        assert_eq!(ZERO, pol.evaluate(&i));
+        assert_eq!(i * 2., pol.derivative().evaluate(&i));
+    }
+
+    #[test]
+    fn complex_matches_integer() {
+        let pol = Polynomial::new(vec![0, 1, -2, 1]);
+        let point = Complex { re: 4., im: 2. };
+        assert_eq!(pol.evaluate(&point), ComplexPolynomial::from(&pol).evaluate(&point));
+        assert_eq!(Some(3), ComplexPolynomial::from(&pol).degree());
+    }
+
     #[test]
     fn evalute_constant() {
         let point = Complex { re: 1., im: 1. };
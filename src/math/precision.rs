@@ -0,0 +1,642 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::str::FromStr;
+
+use dashu_float::round::mode::HalfAway;
+use dashu_float::{DBig, FBig};
+
+use crate::math::complex::Complex;
+use crate::Field;
+
+// numbers with more bits than an f64 for deep zooms. below widths of about 1e-13 the pixels of a
+// view are only a few ulps apart, so neighbouring starting points collapse into the same f64 and
+// the picture turns into blocks. the types here carry the extra bits through the field mapping
+// and newton's method, Precision picks the cheapest one that is enough for a view.
+
+// binary float of any precision, the arithmetic of BigFloat and the parsing of every type
+type Binary = FBig<HalfAway, 2>;
+
+// bits of the mantissa the double-double and quad-double types can be trusted with,
+// a few less than the 106 and 212 of their parts since the last operations round
+const DOUBLE_DOUBLE_BITS: usize = 100;
+const QUAD_DOUBLE_BITS: usize = 200;
+
+// bits on top of the ones telling the pixels apart, newton's method loses a few
+// near the borders of the basins, where neighbouring points part ways
+const MARGIN_BITS: f64 = 8.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Double,
+    DoubleDouble,
+    QuadDouble,
+    // BigFloat with this many bits
+    Arbitrary(usize),
+}
+
+impl Precision {
+    // the cheapest precision the pixels of the field can still be told apart with
+    pub fn for_field(field: &Field) -> Precision {
+        match bits_for(field) {
+            bits if bits <= f64::MANTISSA_DIGITS as usize => Precision::Double,
+            bits if bits <= DOUBLE_DOUBLE_BITS => Precision::DoubleDouble,
+            bits if bits <= QUAD_DOUBLE_BITS => Precision::QuadDouble,
+            bits => Precision::Arbitrary(bits.next_multiple_of(64)),
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        match self {
+            Precision::Double => f64::MANTISSA_DIGITS as usize,
+            Precision::DoubleDouble => DOUBLE_DOUBLE_BITS,
+            Precision::QuadDouble => QUAD_DOUBLE_BITS,
+            Precision::Arbitrary(bits) => *bits,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Precision::Double => "double".to_string(),
+            Precision::DoubleDouble => "double-double".to_string(),
+            Precision::QuadDouble => "quad-double".to_string(),
+            Precision::Arbitrary(bits) => format!("{} bits", bits),
+        }
+    }
+
+    // roughly how many f64 evaluations of a polynomial one evaluation at this precision takes,
+    // measured on the cubic, so the work limits still say how long a render runs
+    pub fn cost(&self) -> u64 {
+        match self {
+            Precision::Double => 1,
+            Precision::DoubleDouble => 8,
+            Precision::QuadDouble => 50,
+            // mostly allocations, the arithmetic itself only shows at thousands of bits
+            Precision::Arbitrary(bits) => 300 + *bits as u64 / 4,
+        }
+    }
+}
+
+// bits needed so the step between two pixels is still a change in the last bits of the
+// coordinates. it is relative, views close to 0 get by with f64 however small they are
+fn bits_for(field: &Field) -> usize {
+    let step = field.size / field.grid as f64;
+    let extent = [
+        field.source.re,
+        field.source.im,
+        field.source.re + field.size,
+        field.source.im + field.size,
+    ]
+    .iter()
+    .fold(step, |extent, v| extent.max(v.abs()));
+    let bits = (extent / step).log2() + MARGIN_BITS;
+    // invalid fields are caught by the validation, they shouldn't ask for endless bits before that
+    if bits.is_finite() {
+        bits.ceil().max(0.) as usize
+    } else {
+        0
+    }
+}
+
+pub trait Real:
+    Clone + Send + Sync + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self>
+{
+    // bits only matters to BigFloat, the other types always have the same precision
+    fn from_f64(value: f64, bits: usize) -> Self;
+    fn from_binary(value: &Binary, bits: usize) -> Self;
+    fn to_f64(&self) -> f64;
+    fn is_zero(&self) -> bool;
+}
+
+// a decimal number, e.g. "-0.74364388703715870475", without rounding it to an f64 first
+pub fn parse<R: Real>(text: &str, bits: usize) -> Option<R> {
+    let decimal = DBig::from_str(text.trim()).ok()?;
+    // the conversion needs the bits of the widest fixed type even if R is narrower
+    let binary = decimal
+        .with_rounding::<HalfAway>()
+        .with_base_and_precision::<2>(bits.max(QUAD_DOUBLE_BITS + 16))
+        .value();
+    Some(R::from_binary(&binary, bits))
+}
+
+// the value split into f64s, each one holding what the ones before it couldn't
+fn limbs<const N: usize>(value: &Binary) -> [f64; N] {
+    let mut rest = value.clone();
+    let mut limbs = [0.; N];
+    for limb in limbs.iter_mut() {
+        *limb = rest.to_f64().value();
+        if *limb == 0. || !limb.is_finite() {
+            break;
+        }
+        rest -= Binary::try_from(*limb).expect("finite");
+    }
+    limbs
+}
+
+impl Real for f64 {
+    fn from_f64(value: f64, _bits: usize) -> Self {
+        value
+    }
+
+    fn from_binary(value: &Binary, _bits: usize) -> Self {
+        value.to_f64().value()
+    }
+
+    fn to_f64(&self) -> f64 {
+        *self
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == 0.
+    }
+}
+
+// the error free transformations the double-double and quad-double arithmetic is made of,
+// see "Library for Double-Double and Quad-Double Arithmetic" by Hida, Li and Bailey.
+// a + b as the rounded sum and its rounding error
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let bb = s - a;
+    (s, (a - (s - bb)) + (b - bb))
+}
+
+// same as two_sum if |a| >= |b|
+fn quick_two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    (s, b - (s - a))
+}
+
+// a * b as the rounded product and its rounding error
+fn two_prod(a: f64, b: f64) -> (f64, f64) {
+    let p = a * b;
+    (p, a.mul_add(b, -p))
+}
+
+// a number as the unevaluated sum hi + lo, about 106 bits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DoubleDouble {
+    pub hi: f64,
+    pub lo: f64,
+}
+
+impl Real for DoubleDouble {
+    fn from_f64(value: f64, _bits: usize) -> Self {
+        DoubleDouble { hi: value, lo: 0. }
+    }
+
+    fn from_binary(value: &Binary, _bits: usize) -> Self {
+        let [hi, lo] = limbs(value);
+        DoubleDouble { hi, lo }
+    }
+
+    fn to_f64(&self) -> f64 {
+        self.hi + self.lo
+    }
+
+    fn is_zero(&self) -> bool {
+        self.hi == 0.
+    }
+}
+
+impl Add for DoubleDouble {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        let (s1, s2) = two_sum(self.hi, rhs.hi);
+        let (t1, t2) = two_sum(self.lo, rhs.lo);
+        let (s1, s2) = quick_two_sum(s1, s2 + t1);
+        let (hi, lo) = quick_two_sum(s1, s2 + t2);
+        DoubleDouble { hi, lo }
+    }
+}
+
+impl Neg for DoubleDouble {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        DoubleDouble { hi: -self.hi, lo: -self.lo }
+    }
+}
+
+impl Sub for DoubleDouble {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        self + -rhs
+    }
+}
+
+impl Mul for DoubleDouble {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        let (p1, p2) = two_prod(self.hi, rhs.hi);
+        let p2 = p2 + (self.hi * rhs.lo + self.lo * rhs.hi);
+        let (hi, lo) = quick_two_sum(p1, p2);
+        DoubleDouble { hi, lo }
+    }
+}
+
+impl Div for DoubleDouble {
+    type Output = Self;
+
+    // long division, every quotient digit is an f64
+    fn div(self, rhs: Self) -> Self {
+        let q1 = self.hi / rhs.hi;
+        let r = self - rhs * DoubleDouble::from_f64(q1, 0);
+        let q2 = r.hi / rhs.hi;
+        let r = r - rhs * DoubleDouble::from_f64(q2, 0);
+        let q3 = r.hi / rhs.hi;
+        let (hi, lo) = quick_two_sum(q1, q2);
+        DoubleDouble { hi, lo } + DoubleDouble::from_f64(q3, 0)
+    }
+}
+
+// a number as the unevaluated sum of four f64s, about 212 bits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuadDouble(pub [f64; 4]);
+
+// a + b + c as a + b with the error in c
+fn three_sum(a: f64, b: f64, c: f64) -> (f64, f64, f64) {
+    let (t1, t2) = two_sum(a, b);
+    let (a, t3) = two_sum(c, t1);
+    let (b, c) = two_sum(t2, t3);
+    (a, b, c)
+}
+
+// same as three_sum, without the error of the second part
+fn three_sum2(a: f64, b: f64, c: f64) -> (f64, f64) {
+    let (t1, t2) = two_sum(a, b);
+    let (a, t3) = two_sum(c, t1);
+    (a, t2 + t3)
+}
+
+// turns the five overlapping parts into four that don't overlap, largest first
+fn renormalize(c0: f64, c1: f64, c2: f64, c3: f64, c4: f64) -> QuadDouble {
+    let (s, c4) = quick_two_sum(c3, c4);
+    let (s, c3) = quick_two_sum(c2, s);
+    let (s, c2) = quick_two_sum(c1, s);
+    let (c0, c1) = quick_two_sum(c0, s);
+
+    let mut s = [c0, c1, 0., 0.];
+    if s[1] != 0. {
+        (s[1], s[2]) = quick_two_sum(s[1], c2);
+        if s[2] != 0. {
+            (s[2], s[3]) = quick_two_sum(s[2], c3);
+            if s[3] != 0. {
+                s[3] += c4;
+            } else {
+                (s[2], s[3]) = quick_two_sum(s[2], c4);
+            }
+        } else {
+            (s[1], s[2]) = quick_two_sum(s[1], c3);
+            if s[2] != 0. {
+                (s[2], s[3]) = quick_two_sum(s[2], c4);
+            } else {
+                (s[1], s[2]) = quick_two_sum(s[1], c4);
+            }
+        }
+    } else {
+        (s[0], s[1]) = quick_two_sum(s[0], c2);
+        if s[1] != 0. {
+            (s[1], s[2]) = quick_two_sum(s[1], c3);
+            if s[2] != 0. {
+                (s[2], s[3]) = quick_two_sum(s[2], c4);
+            } else {
+                (s[1], s[2]) = quick_two_sum(s[1], c4);
+            }
+        } else {
+            (s[0], s[1]) = quick_two_sum(s[0], c3);
+            if s[1] != 0. {
+                (s[1], s[2]) = quick_two_sum(s[1], c4);
+            } else {
+                (s[0], s[1]) = quick_two_sum(s[0], c4);
+            }
+        }
+    }
+    QuadDouble(s)
+}
+
+impl Real for QuadDouble {
+    fn from_f64(value: f64, _bits: usize) -> Self {
+        QuadDouble([value, 0., 0., 0.])
+    }
+
+    fn from_binary(value: &Binary, _bits: usize) -> Self {
+        QuadDouble(limbs(value))
+    }
+
+    fn to_f64(&self) -> f64 {
+        self.0[0] + self.0[1]
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0[0] == 0.
+    }
+}
+
+impl Add for QuadDouble {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        let (a, b) = (self.0, rhs.0);
+        let (s0, t0) = two_sum(a[0], b[0]);
+        let (s1, t1) = two_sum(a[1], b[1]);
+        let (s2, t2) = two_sum(a[2], b[2]);
+        let (s3, t3) = two_sum(a[3], b[3]);
+
+        let (s1, t0) = two_sum(s1, t0);
+        let (s2, t0, t1) = three_sum(s2, t0, t1);
+        let (s3, t0) = three_sum2(s3, t0, t2);
+        renormalize(s0, s1, s2, s3, t0 + t1 + t3)
+    }
+}
+
+impl Neg for QuadDouble {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        QuadDouble(self.0.map(|v| -v))
+    }
+}
+
+impl Sub for QuadDouble {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        self + -rhs
+    }
+}
+
+impl Mul for QuadDouble {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        let (a, b) = (self.0, rhs.0);
+        let (p0, q0) = two_prod(a[0], b[0]);
+        let (p1, q1) = two_prod(a[0], b[1]);
+        let (p2, q2) = two_prod(a[1], b[0]);
+        let (p3, q3) = two_prod(a[0], b[2]);
+        let (p4, q4) = two_prod(a[1], b[1]);
+        let (p5, q5) = two_prod(a[2], b[0]);
+
+        let (p1, p2, q0) = three_sum(p1, p2, q0);
+        // (p2, q1, q2) + (p3, p4, p5) as (s0, s1, s2)
+        let (p2, q1, q2) = three_sum(p2, q1, q2);
+        let (p3, p4, p5) = three_sum(p3, p4, p5);
+        let (s0, t0) = two_sum(p2, p3);
+        let (s1, t1) = two_sum(q1, p4);
+        let s2 = q2 + p5;
+        let (s1, t0) = two_sum(s1, t0);
+        let s2 = s2 + (t0 + t1);
+
+        // the parts of the order of eps^3
+        let s1 = s1 + (a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + q0 + q3 + q4 + q5);
+        renormalize(p0, p1, s0, s1, s2)
+    }
+}
+
+impl Div for QuadDouble {
+    type Output = Self;
+
+    // long division like for DoubleDouble, with one more digit
+    fn div(self, rhs: Self) -> Self {
+        let q0 = self.0[0] / rhs.0[0];
+        let r = self - rhs * QuadDouble::from_f64(q0, 0);
+        let q1 = r.0[0] / rhs.0[0];
+        let r = r - rhs * QuadDouble::from_f64(q1, 0);
+        let q2 = r.0[0] / rhs.0[0];
+        let r = r - rhs * QuadDouble::from_f64(q2, 0);
+        let q3 = r.0[0] / rhs.0[0];
+        renormalize(q0, q1, q2, q3, 0.)
+    }
+}
+
+// binary float with as many bits as the view needs, a lot slower than the fixed types
+#[derive(Debug, Clone, PartialEq)]
+pub struct BigFloat(Binary);
+
+impl Real for BigFloat {
+    fn from_f64(value: f64, bits: usize) -> Self {
+        let value = Binary::try_from(value).expect("finite");
+        BigFloat(value.with_precision(bits).value())
+    }
+
+    fn from_binary(value: &Binary, bits: usize) -> Self {
+        BigFloat(value.clone().with_precision(bits).value())
+    }
+
+    fn to_f64(&self) -> f64 {
+        self.0.to_f64().value()
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.repr().is_zero()
+    }
+}
+
+impl BigFloat {
+    // decimal text with as many digits as the bits hold, parse reads it back to the same value
+    pub fn to_decimal(&self) -> String {
+        let digits = (self.0.precision() as f64 * std::f64::consts::LOG10_2).ceil() as usize + 2;
+        self.0.clone().with_base_and_precision::<10>(digits).value().to_string()
+    }
+}
+
+impl Add for BigFloat {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        BigFloat(self.0 + rhs.0)
+    }
+}
+
+impl Sub for BigFloat {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        BigFloat(self.0 - rhs.0)
+    }
+}
+
+impl Mul for BigFloat {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        BigFloat(self.0 * rhs.0)
+    }
+}
+
+impl Div for BigFloat {
+    type Output = Self;
+
+    // panics on zero, callers check is_zero first
+    fn div(self, rhs: Self) -> Self {
+        BigFloat(self.0 / rhs.0)
+    }
+}
+
+// Complex with the parts in one of the types above
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreciseComplex<R> {
+    pub re: R,
+    pub im: R,
+}
+
+impl<R: Real> PreciseComplex<R> {
+    pub fn from_complex(c: &Complex, bits: usize) -> Self {
+        PreciseComplex {
+            re: R::from_f64(c.re, bits),
+            im: R::from_f64(c.im, bits),
+        }
+    }
+
+    pub fn to_complex(&self) -> Complex {
+        Complex {
+            re: self.re.to_f64(),
+            im: self.im.to_f64(),
+        }
+    }
+
+    pub fn norm_sqr(&self) -> R {
+        self.re.clone() * self.re.clone() + self.im.clone() * self.im.clone()
+    }
+}
+
+impl<R: Real> Add for PreciseComplex<R> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        PreciseComplex {
+            re: self.re + rhs.re,
+            im: self.im + rhs.im,
+        }
+    }
+}
+
+impl<R: Real> Sub for PreciseComplex<R> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        PreciseComplex {
+            re: self.re - rhs.re,
+            im: self.im - rhs.im,
+        }
+    }
+}
+
+impl<R: Real> Mul for PreciseComplex<R> {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        PreciseComplex {
+            re: self.re.clone() * rhs.re.clone() - self.im.clone() * rhs.im.clone(),
+            im: self.re * rhs.im + self.im * rhs.re,
+        }
+    }
+}
+
+impl<R: Real> Div for PreciseComplex<R> {
+    type Output = Self;
+
+    // same as for Complex, BigFloat panics if rhs is zero
+    fn div(self, rhs: Self) -> Self {
+        let k = rhs.norm_sqr();
+        let re = (self.re.clone() * rhs.re.clone() + self.im.clone() * rhs.im.clone()) / k.clone();
+        let im = (self.im * rhs.re - self.re * rhs.im) / k;
+        PreciseComplex { re, im }
+    }
+}
+
+// the polynomial with these coefficients, lowest first, at z
+pub fn evaluate<R: Real>(coeff: &[PreciseComplex<R>], z: &PreciseComplex<R>) -> PreciseComplex<R> {
+    let mut coeff = coeff.iter().rev();
+    let first = match coeff.next() {
+        Some(c) => c.clone(),
+        None => PreciseComplex::from_complex(&Complex { re: 0., im: 0. }, 0),
+    };
+    coeff.fold(first, |value, c| value * z.clone() + c.clone())
+}
+
+// the corner of a view as the decimal text it came in, with the digits an f64 can't hold.
+// requests only keep it when the view needs more precision than f64
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExactPoint {
+    pub re: String,
+    pub im: String,
+}
+
+impl ExactPoint {
+    // none if the field is fine with f64 or the numbers can't be parsed exactly
+    pub fn for_field(re: &str, im: &str, field: &Field) -> Option<ExactPoint> {
+        if Precision::for_field(field) == Precision::Double {
+            return None;
+        }
+        let valid = |text: &str| DBig::from_str(text.trim()).is_ok();
+        (valid(re) && valid(im)).then(|| ExactPoint {
+            re: re.trim().to_string(),
+            im: im.trim().to_string(),
+        })
+    }
+
+    pub fn to_precise<R: Real>(&self, bits: usize) -> Option<PreciseComplex<R>> {
+        Some(PreciseComplex {
+            re: parse(&self.re, bits)?,
+            im: parse(&self.im, bits)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // relative error of an operation against the same one on BigFloat with plenty of bits
+    fn error<R: Real>(op: impl Fn(R, R) -> R, big: impl Fn(BigFloat, BigFloat) -> BigFloat) -> f64 {
+        let (a, b) = ("-0.74364388703715870475219150611477", "0.13182590420531197049865951989346");
+        let exact = big(parse(a, 400).unwrap(), parse(b, 400).unwrap());
+        let result = op(parse(a, 0).unwrap(), parse(b, 0).unwrap());
+        let diff = result - R::from_binary(&exact.0, 0);
+        (diff.to_f64() / exact.to_f64()).abs()
+    }
+
+    #[test]
+    fn double_double_arithmetic() {
+        let bound = 2f64.powi(-(DOUBLE_DOUBLE_BITS as i32));
+        assert!(error::<DoubleDouble>(|a, b| a + b, |a, b| a + b) < bound);
+        assert!(error::<DoubleDouble>(|a, b| a - b, |a, b| a - b) < bound);
+        assert!(error::<DoubleDouble>(|a, b| a * b, |a, b| a * b) < bound);
+        assert!(error::<DoubleDouble>(|a, b| a / b, |a, b| a / b) < bound);
+    }
+
+    #[test]
+    fn quad_double_arithmetic() {
+        let bound = 2f64.powi(-(QUAD_DOUBLE_BITS as i32));
+        assert!(error::<QuadDouble>(|a, b| a + b, |a, b| a + b) < bound);
+        assert!(error::<QuadDouble>(|a, b| a - b, |a, b| a - b) < bound);
+        assert!(error::<QuadDouble>(|a, b| a * b, |a, b| a * b) < bound);
+        assert!(error::<QuadDouble>(|a, b| a / b, |a, b| a / b) < bound);
+    }
+
+    #[test]
+    fn precision_follows_the_zoom() {
+        let field = |tx: f64, tw: f64| Field { source: Complex { re: tx, im: 0.1 }, size: tw, grid: 512 };
+        assert_eq!(Precision::Double, Precision::for_field(&field(-2., 4.)));
+        assert_eq!(Precision::Double, Precision::for_field(&field(-0.7, 1e-10)));
+        assert_eq!(Precision::DoubleDouble, Precision::for_field(&field(-0.7, 1e-13)));
+        assert_eq!(Precision::QuadDouble, Precision::for_field(&field(-0.7, 1e-40)));
+        assert_eq!(Precision::Arbitrary(320), Precision::for_field(&field(-0.7, 1e-80)));
+        // relative, tiny views around 0 don't need more bits
+        let around_zero = Field { source: Complex { re: -1e-20, im: -1e-20 }, size: 2e-20, grid: 512 };
+        assert_eq!(Precision::Double, Precision::for_field(&around_zero));
+    }
+
+    #[test]
+    fn exact_point_keeps_digits() {
+        let deep = Field { source: Complex { re: -0.7, im: 0.1 }, size: 1e-20, grid: 512 };
+        let exact = ExactPoint::for_field("-0.700000000000000000001", "0.1", &deep).unwrap();
+        let source: PreciseComplex<DoubleDouble> = exact.to_precise(0).unwrap();
+        let offset = source.re - parse("-0.7", 0).unwrap();
+        assert!((offset.to_f64() + 1e-21).abs() < 1e-30);
+        let shallow = Field { size: 1., ..deep };
+        assert_eq!(None, ExactPoint::for_field("-0.7", "0.1", &shallow));
+    }
+}
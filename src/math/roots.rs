@@ -0,0 +1,188 @@
+use std::f64::consts::PI;
+
+use crate::math::complex::{Complex, ZERO};
+use crate::math::polynomial::{ComplexPolynomial, Differentiable, Polynomial};
+
+const MAX_ITER: usize = 1000;
+const TOLERANCE: f64 = 1e-14;
+
+// a root and how many times it is repeated
+#[derive(Debug, Clone, Copy)]
+pub struct Root {
+    pub value: Complex,
+    pub multiplicity: usize,
+}
+
+// all the complex roots of the polynomial, found with the durand-kerner method.
+// the method finds degree many roots, a repeated root shows up as a cluster of
+// approximations around it. clusters are merged (with the same tolerance as
+// comparing complex numbers) and their mean is the root, it is much more precise
+// than any of the single approximations.
+pub fn roots(pol: &Polynomial) -> Vec<Root> {
+    let pol = ComplexPolynomial::from(pol);
+    let mut clusters: Vec<Vec<Complex>> = Vec::new();
+    for value in all_roots(&pol) {
+        match clusters.iter_mut().find(|c| mean(c) == value) {
+            Some(cluster) => cluster.push(value),
+            None => clusters.push(vec![value]),
+        }
+    }
+    clusters
+        .iter()
+        .map(|c| Root {
+            value: clean(polish(&pol, mean(c), c.len())),
+            multiplicity: c.len(),
+        })
+        .collect()
+}
+
+// the degree many approximations of durand-kerner, repeated roots show up
+// as several approximations close to each other
+pub fn all_roots(pol: &ComplexPolynomial) -> Vec<Complex> {
+    let degree = match pol.degree() {
+        Some(degree) if degree > 0 => degree,
+        _ => return vec![],
+    };
+    let coeff = pol.coefficients();
+    let lead = coeff[degree];
+    let monic: Vec<Complex> = coeff[..degree].iter().map(|c| *c / lead).collect();
+
+    // cauchy's bound, every root lies within this radius
+    let radius = 1.0 + monic.iter().map(|c| c.abs()).fold(0.0, f64::max);
+    // start on a circle, the offset keeps the guesses off symmetric positions
+    let mut z: Vec<Complex> = (0..degree)
+        .map(|k| {
+            let angle = 2.0 * PI * k as f64 / degree as f64 + 0.4;
+            Complex { re: angle.cos(), im: angle.sin() } * radius
+        })
+        .collect();
+
+    for _ in 0..MAX_ITER {
+        let mut change: f64 = 0.0;
+        for k in 0..degree {
+            let mut denominator = Complex { re: 1.0, im: 0.0 };
+            for j in 0..degree {
+                if j != k {
+                    denominator = denominator * (z[k] - z[j]);
+                }
+            }
+            let step = evaluate_monic(&monic, z[k]) / denominator;
+            if step.re.is_finite() && step.im.is_finite() {
+                z[k] = z[k] - step;
+                change = change.max(step.abs());
+            }
+        }
+        if change < TOLERANCE {
+            break;
+        }
+    }
+    z
+}
+
+// a root of multiplicity m is a simple root of the (m - 1)th derivative,
+// where a few newton steps get it to full precision
+fn polish(pol: &ComplexPolynomial, root: Complex, multiplicity: usize) -> Complex {
+    if multiplicity == 1 {
+        return root;
+    }
+    let mut q = pol.derivative();
+    for _ in 2..multiplicity {
+        q = q.derivative();
+    }
+    let dq = q.derivative();
+
+    let mut z = root;
+    for _ in 0..5 {
+        let step = q.evaluate(&z) / dq.evaluate(&z);
+        if !(step.re.is_finite() && step.im.is_finite()) {
+            break;
+        }
+        z = z - step;
+    }
+    // the polishing should only ever move the root a tiny bit
+    if z == root {
+        z
+    } else {
+        root
+    }
+}
+
+// the points where the derivative is zero, where newton's method breaks down
+pub fn critical_points(pol: &Polynomial) -> Vec<Root> {
+    roots(&pol.derivative())
+}
+
+// horner's method for a monic polynomial, the leading 1 is implicit
+fn evaluate_monic(coeff: &[Complex], z: Complex) -> Complex {
+    coeff.iter().rev().fold(Complex { re: 1.0, im: 0.0 }, |acc, c| acc * z + *c)
+}
+
+fn mean(values: &[Complex]) -> Complex {
+    let sum = values.iter().fold(ZERO, |acc, v| acc + *v);
+    sum * (1.0 / values.len() as f64)
+}
+
+// rounding noise, e.g. 1e-17 for a real root, is snapped to zero
+fn clean(z: Complex) -> Complex {
+    let snap = |v: f64| if v.abs() < 1e-10 { 0.0 } else { v };
+    Complex { re: snap(z.re), im: snap(z.im) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut roots: Vec<Root>) -> Vec<Root> {
+        roots.sort_by(|a, b| (a.value.re, a.value.im).partial_cmp(&(b.value.re, b.value.im)).unwrap());
+        roots
+    }
+
+    #[test]
+    fn roots_of_unity() {
+        // x^3 - 1
+        let roots = sorted(roots(&Polynomial::new(vec![-1, 0, 0, 1])));
+        assert_eq!(3, roots.len());
+        let half = 3f64.sqrt() / 2.0;
+        assert_eq!(Complex { re: -0.5, im: -half }, roots[0].value);
+        assert_eq!(Complex { re: -0.5, im: half }, roots[1].value);
+        assert_eq!(Complex { re: 1.0, im: 0.0 }, roots[2].value);
+        assert!((roots[2].value.re - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn repeated_roots() {
+        // (x - 1)^2 (x + 2) = x^3 - 3x + 2
+        let roots = sorted(roots(&Polynomial::new(vec![2, -3, 0, 1])));
+        assert_eq!(2, roots.len());
+        assert_eq!(Complex { re: -2.0, im: 0.0 }, roots[0].value);
+        assert_eq!(1, roots[0].multiplicity);
+        assert_eq!(Complex { re: 1.0, im: 0.0 }, roots[1].value);
+        assert!((roots[1].value.re - 1.0).abs() < 1e-12);
+        assert_eq!(2, roots[1].multiplicity);
+    }
+
+    #[test]
+    fn critical_points_of_cubic() {
+        // x^3 - 1 has a double critical point at 0
+        let points = critical_points(&Polynomial::new(vec![-1, 0, 0, 1]));
+        assert_eq!(1, points.len());
+        assert_eq!(2, points[0].multiplicity);
+        assert_eq!(ZERO, points[0].value);
+    }
+
+    #[test]
+    fn complex_coefficients() {
+        let expected = [Complex { re: 1., im: 2. }, Complex { re: -0.5, im: 0.25 }, Complex { re: 0., im: -1. }];
+        let found = all_roots(&ComplexPolynomial::from_roots(&expected));
+        assert_eq!(3, found.len());
+        for root in expected {
+            assert!(found.contains(&root), "{} not in {:?}", root, found);
+        }
+    }
+
+    #[test]
+    fn constant_has_no_roots() {
+        assert!(roots(&Polynomial::new(vec![3])).is_empty());
+        assert!(roots(&Polynomial::new(vec![0, 0])).is_empty());
+    }
+}
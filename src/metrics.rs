@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::queue::QueueStats;
+
+// upper bounds of the render latency buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+// counters of the server, exposed in the prometheus text format at /metrics.
+// rates (points solved per second, ...) are left to prometheus, e.g. rate(newton_points_solved_total[1m])
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // by route and status
+    requests: BTreeMap<(&'static str, u16), u64>,
+    // cumulative counts for each bucket in LATENCY_BUCKETS
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_count: u64,
+    latency_sum: f64,
+    points: u64,
+    iterations: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+impl Metrics {
+    pub fn request(&self, route: &'static str, status: u16) {
+        *self.inner.lock().unwrap().requests.entry((route, status)).or_default() += 1;
+    }
+
+    // a finished render, cache hits don't count
+    pub fn render(&self, duration: Duration, points: u64, iterations: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let seconds = duration.as_secs_f64();
+        for (count, bound) in inner.latency_buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        inner.latency_count += 1;
+        inner.latency_sum += seconds;
+        inner.points += points;
+        inner.iterations += iterations;
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if hit {
+            inner.cache_hits += 1;
+        } else {
+            inner.cache_misses += 1;
+        }
+    }
+
+    // the metrics in the prometheus text exposition format,
+    // the gauges owned by other parts of the server are passed in
+    pub fn export(&self, queue: &QueueStats, cache_bytes: usize) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "newton_http_requests_total", "counter", "HTTP requests by route and status.");
+        for ((route, status), count) in &inner.requests {
+            let _ = writeln!(out, "newton_http_requests_total{{route=\"{}\",status=\"{}\"}} {}", route, status, count);
+        }
+
+        header(&mut out, "newton_render_duration_seconds", "histogram", "Time spent rendering, cache hits excluded.");
+        for (count, bound) in inner.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "newton_render_duration_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+        }
+        let _ = writeln!(out, "newton_render_duration_seconds_bucket{{le=\"+Inf\"}} {}", inner.latency_count);
+        let _ = writeln!(out, "newton_render_duration_seconds_sum {}", inner.latency_sum);
+        let _ = writeln!(out, "newton_render_duration_seconds_count {}", inner.latency_count);
+
+        let values = [
+            ("newton_points_solved_total", "counter", "Points solved with newton's method.", inner.points),
+            ("newton_iterations_total", "counter", "Newton iterations over all the solved points.", inner.iterations),
+            ("newton_cache_hits_total", "counter", "Renders served from the cache.", inner.cache_hits),
+            ("newton_cache_misses_total", "counter", "Renders not found in the cache.", inner.cache_misses),
+            ("newton_cache_bytes", "gauge", "Size of the cached renders.", cache_bytes as u64),
+            ("newton_queue_running", "gauge", "Renders running.", queue.running as u64),
+            ("newton_queue_queued", "gauge", "Renders waiting for a slot.", queue.queued as u64),
+            ("newton_queue_max_running", "gauge", "Renders allowed to run at once.", queue.max_running as u64),
+        ];
+        for (name, kind, help, value) in values {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.render(Duration::from_millis(200), 10, 100);
+        metrics.render(Duration::from_secs(20), 10, 100);
+        let queue = QueueStats { running: 1, queued: 2, max_running: 4, max_queued: 64 };
+        let out = metrics.export(&queue, 0);
+        assert!(out.contains("newton_render_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(out.contains("newton_render_duration_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(out.contains("newton_render_duration_seconds_bucket{le=\"30\"} 2\n"));
+        assert!(out.contains("newton_render_duration_seconds_count 2\n"));
+        assert!(out.contains("newton_iterations_total 200\n"));
+        assert!(out.contains("newton_queue_queued 2\n"));
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+// admission queue in front of the renders. at most max_running renders run at once,
+// the others wait for their turn. waiting clients take turns, so a client queueing
+// a hundred tiles doesn't starve one asking for a single image
+pub struct RenderQueue {
+    state: Mutex<State>,
+    max_running: usize,
+    max_queued: usize,
+    max_queued_per_client: usize,
+}
+
+struct State {
+    running: usize,
+    queued: usize,
+    // clients with waiting renders, in the order they get their next turn
+    turns: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<Waiter>>,
+    next_id: u64,
+    // moving average of how long a render holds its slot, used to guess Retry-After
+    average_render: Duration,
+}
+
+struct Waiter {
+    id: u64,
+    ready: oneshot::Sender<()>,
+}
+
+// the queue is full, or the client already has too many renders waiting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueFull {
+    pub retry_after: Duration,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct QueueStats {
+    pub running: usize,
+    pub queued: usize,
+    pub max_running: usize,
+    pub max_queued: usize,
+}
+
+// a running render, its slot goes to the next waiting render when dropped.
+// it owns a handle on the queue so it can be moved into a task that outlives the request handler
+pub struct Permit {
+    queue: Arc<RenderQueue>,
+    started: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.queue.release(Some(self.started.elapsed()));
+    }
+}
+
+// removes a waiter that gave up (the client went away) from the queue
+struct Waiting<'a> {
+    queue: &'a RenderQueue,
+    client: &'a str,
+    id: u64,
+    done: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut state = self.queue.state.lock().unwrap();
+        let waiters = state.waiting.get_mut(self.client);
+        match waiters.and_then(|w| w.iter().position(|w| w.id == self.id).map(|i| (w, i))) {
+            Some((waiters, i)) => {
+                waiters.remove(i);
+                if waiters.is_empty() {
+                    state.waiting.remove(self.client);
+                    state.turns.retain(|c| c != self.client);
+                }
+                state.queued -= 1;
+            }
+            // the slot was handed over just before the waiter gave up, pass it on
+            None => {
+                drop(state);
+                self.queue.release(None);
+            }
+        }
+    }
+}
+
+impl RenderQueue {
+    pub fn new(max_running: usize, max_queued: usize, max_queued_per_client: usize) -> Self {
+        RenderQueue {
+            state: Mutex::new(State {
+                running: 0,
+                queued: 0,
+                turns: VecDeque::new(),
+                waiting: HashMap::new(),
+                next_id: 0,
+                average_render: Duration::from_secs(1),
+            }),
+            max_running,
+            max_queued,
+            max_queued_per_client,
+        }
+    }
+
+    // waits for a free slot, or fails right away if the queue is full
+    pub async fn acquire(self: &Arc<Self>, client: &str) -> Result<Permit, QueueFull> {
+        let (ready, wait) = oneshot::channel();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.max_running && state.queued == 0 {
+                state.running += 1;
+                return Ok(self.permit());
+            }
+
+            let client_queued = state.waiting.get(client).map_or(0, |w| w.len());
+            if state.queued >= self.max_queued || client_queued >= self.max_queued_per_client {
+                return Err(QueueFull {
+                    retry_after: self.retry_after(&state),
+                });
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+            state.queued += 1;
+            if client_queued == 0 {
+                state.turns.push_back(client.to_string());
+            }
+            state
+                .waiting
+                .entry(client.to_string())
+                .or_default()
+                .push_back(Waiter { id, ready });
+            id
+        };
+
+        let mut waiting = Waiting {
+            queue: self.as_ref(),
+            client,
+            id,
+            done: false,
+        };
+        // the sender is only dropped after sending
+        wait.await.unwrap();
+        waiting.done = true;
+        Ok(self.permit())
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            running: state.running,
+            queued: state.queued,
+            max_running: self.max_running,
+            max_queued: self.max_queued,
+        }
+    }
+
+    fn permit(self: &Arc<Self>) -> Permit {
+        Permit {
+            queue: self.clone(),
+            started: Instant::now(),
+        }
+    }
+
+    // hands the slot of a finished render to the next client in turn, or frees it
+    fn release(&self, render_time: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(render_time) = render_time {
+            state.average_render = (state.average_render * 7 + render_time) / 8;
+        }
+
+        while let Some(client) = state.turns.pop_front() {
+            let waiters = state.waiting.get_mut(&client).unwrap();
+            let waiter = waiters.pop_front().unwrap();
+            if waiters.is_empty() {
+                state.waiting.remove(&client);
+            } else {
+                state.turns.push_back(client);
+            }
+            state.queued -= 1;
+            // the waiter removes itself under the lock when it gives up, so it is still listening
+            if waiter.ready.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+
+    // rough time until a new render would get a slot
+    fn retry_after(&self, state: &State) -> Duration {
+        let ahead = (state.queued + state.running) as u32;
+        let wait = state.average_render * ahead / self.max_running as u32;
+        Duration::from_secs(wait.as_secs_f64().ceil().max(1.0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    // the output of the future if it completes soon, it is left pending otherwise
+    async fn ready<F: Future + Unpin>(future: F) -> Option<F::Output> {
+        tokio::time::timeout(Duration::from_millis(10), future).await.ok()
+    }
+
+    #[tokio::test]
+    async fn rejects_when_full() {
+        let queue = Arc::new(RenderQueue::new(1, 1, 1));
+        let running = queue.acquire("a").await.unwrap();
+        let waiting = queue.acquire("b");
+        tokio::pin!(waiting);
+        assert!(ready(&mut waiting).await.is_none());
+        assert_eq!(1, queue.stats().queued);
+
+        let full = queue.acquire("c").await.err().unwrap();
+        assert!(full.retry_after >= Duration::from_secs(1));
+
+        drop(running);
+        assert!(ready(&mut waiting).await.unwrap().is_ok());
+        assert_eq!(0, queue.stats().queued);
+    }
+
+    #[tokio::test]
+    async fn clients_take_turns() {
+        let queue = Arc::new(RenderQueue::new(1, 10, 10));
+        let running = queue.acquire("x").await.unwrap();
+        let (a1, a2, b) = (queue.acquire("a"), queue.acquire("a"), queue.acquire("b"));
+        tokio::pin!(a1, a2, b);
+        assert!(ready(&mut a1).await.is_none());
+        assert!(ready(&mut a2).await.is_none());
+        assert!(ready(&mut b).await.is_none());
+        assert_eq!(3, queue.stats().queued);
+
+        drop(running);
+        let permit = ready(&mut a1).await.unwrap();
+        drop(permit);
+        // b gets its turn before the second render of a
+        assert!(ready(&mut a2).await.is_none());
+        assert!(ready(&mut b).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn waiter_that_gives_up_leaves_the_queue() {
+        let queue = Arc::new(RenderQueue::new(1, 10, 10));
+        let running = queue.acquire("a").await.unwrap();
+        {
+            let waiting = queue.acquire("b");
+            tokio::pin!(waiting);
+            assert!(ready(&mut waiting).await.is_none());
+            assert_eq!(1, queue.stats().queued);
+        }
+        assert_eq!(0, queue.stats().queued);
+        drop(running);
+        assert_eq!(0, queue.stats().running);
+    }
+}
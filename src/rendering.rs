@@ -1,44 +1,182 @@
-use image::{Rgb, RgbImage};
+use image::{DynamicImage, Rgb, Rgb32FImage, RgbImage};
 use std::f32::consts::PI;
 
-use crate::{Field, Solution};
+use crate::{distinct_roots, root_index, Field, Solution};
+
+// how the number of iterations is turned into the brightness of a pixel
+#[derive(Clone, Copy)]
+pub enum Shading {
+    // brightness is linear in iter / max_iter
+    Linear,
+    // same as Linear with the continuous iteration count, without the bands between iterations
+    Smooth,
+    // iterations are mapped through the cumulative histogram of the whole image,
+    // so the contrast adapts to the iterations that are actually present in the view
+    Histogram,
+    // same as Histogram, but every root (basin) gets its own histogram
+    HistogramPerRoot,
+}
+
+impl Shading {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(Shading::Linear),
+            "smooth" => Some(Shading::Smooth),
+            "histogram" => Some(Shading::Histogram),
+            "histogram_root" => Some(Shading::HistogramPerRoot),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shading::Linear => "linear",
+            Shading::Smooth => "smooth",
+            Shading::Histogram => "histogram",
+            Shading::HistogramPerRoot => "histogram_root",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RenderOptions {
+    pub shading: Shading,
+    // if set, the image gets the relief effect, see apply_lighting
+    pub light: Option<Light>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            shading: Shading::Linear,
+            light: None,
+        }
+    }
+}
 
 // turn a set of solutions and a field into a a pretty picture
 // todo I need to find a better abstraction for representing solutions of the field.
 // currently the logic of solving a field is not encapsulated well and this method needs
 // to know how solution for a field works.
 pub fn render_image(solutions: &Vec<Solution>, field: &Field, max_iter: u32) -> RgbImage {
-    let mut image = RgbImage::new(field.grid, field.grid);
-    let mut iter = solutions.iter();
+    render_image_with(solutions, field, max_iter, &RenderOptions::default())
+}
+
+// same as render_image, but with control over the shading and post-processing
+pub fn render_image_with(
+    solutions: &[Solution],
+    field: &Field,
+    max_iter: u32,
+    options: &RenderOptions,
+) -> RgbImage {
+    let image = render_image_f32(solutions, field, max_iter, options);
+    DynamicImage::ImageRgb32F(image).into_rgb8()
+}
+
+// renders the image keeping every channel as a float between 0 and 1,
+// the colors are only quantized when the image is encoded, so that 16 bit
+// and floating point outputs don't end up with the banding of 8 bit images
+pub fn render_image_f32(
+    solutions: &[Solution],
+    field: &Field,
+    max_iter: u32,
+    options: &RenderOptions,
+) -> Rgb32FImage {
+    let shades = shade_values(solutions, max_iter, &options.shading);
+
+    let mut image = Rgb32FImage::new(field.grid, field.grid);
+    let mut iter = solutions.iter().zip(shades.iter());
     for i in 0..field.grid {
         for j in 0..field.grid {
-            let solution = iter.next().expect("not enough values in solutions");
-            let (r, g, b) = color_from_root(solution, max_iter);
+            let (solution, shade) = iter.next().expect("not enough values in solutions");
+            let (r, g, b) = color_from_root(solution, *shade);
             image.put_pixel(i, j, Rgb([r, g, b]));
         }
     }
 
+    if let Some(light) = &options.light {
+        apply_lighting(&mut image, solutions, field, light);
+    }
+
     image
 }
 
+// for every solution, a value between 0 and 1 describing how "late" it converged
+fn shade_values(solutions: &[Solution], max_iter: u32, shading: &Shading) -> Vec<f32> {
+    match shading {
+        Shading::Linear => solutions
+            .iter()
+            .map(|s| s.iter as f32 / max_iter as f32)
+            .collect(),
+        Shading::Smooth => solutions
+            .iter()
+            .map(|s| s.smooth as f32 / max_iter as f32)
+            .collect(),
+        Shading::Histogram => {
+            let cdf = cumulative_histogram(solutions.iter().map(|s| s.iter), max_iter);
+            solutions.iter().map(|s| cdf[s.iter as usize]).collect()
+        }
+        Shading::HistogramPerRoot => {
+            // points that didn't converge to any root share the last histogram
+            let roots = distinct_roots(solutions, max_iter);
+            let groups: Vec<usize> = solutions
+                .iter()
+                .map(|s| root_index(&roots, &s.root).unwrap_or(roots.len()))
+                .collect();
+            let cdfs: Vec<Vec<f32>> = (0..=roots.len())
+                .map(|g| {
+                    let iters = solutions
+                        .iter()
+                        .zip(groups.iter())
+                        .filter(|(_, group)| **group == g)
+                        .map(|(s, _)| s.iter);
+                    cumulative_histogram(iters, max_iter)
+                })
+                .collect();
+            solutions
+                .iter()
+                .zip(groups.iter())
+                .map(|(s, g)| cdfs[*g][s.iter as usize])
+                .collect()
+        }
+    }
+}
+
+// normalized cumulative histogram of iteration counts, the i-th value
+// is the fraction of the iterations that are less or equal to i
+fn cumulative_histogram(iters: impl Iterator<Item = u32>, max_iter: u32) -> Vec<f32> {
+    let mut histogram = vec![0u32; max_iter as usize + 1];
+    for iter in iters {
+        histogram[usize::min(iter as usize, max_iter as usize)] += 1;
+    }
+    let total = histogram.iter().sum::<u32>().max(1) as f32;
+
+    let mut acc = 0;
+    histogram
+        .iter()
+        .map(|count| {
+            acc += count;
+            acc as f32 / total
+        })
+        .collect()
+}
+
 // calculates the rgb color of a solution
 // the idea is that we start with HSL color definition, where
 // hue is based on arg (angle of the complex number)
 // sat is based on abs (size of the complex value)
-// lum is based on the number of iterations it took to calculate
+// lum is based on the number of iterations it took to calculate (shade)
 // todo:
 // - cache hue and sat, hue and sat depend on the root and there are only N roots for n-degree polynomial
 //   but we ran the same expensive calculations for each point in the image
-fn color_from_root(solution: &Solution, max_iter: u32) -> (u8, u8, u8) {
-    let iter = solution.iter as f32;
-    let max_iter = max_iter as f32;
+fn color_from_root(solution: &Solution, shade: f32) -> (f32, f32, f32) {
     let arg = solution.root.arg() as f32;
     let abs = solution.root.abs() as f32;
     let hue = clamp01(f32::abs(0.5 - arg / (PI * 2.)));
     let sat = clamp01(f32::abs(0.5 / abs));
-    let lum = clamp01(f32::abs(0.5 - iter / max_iter));
+    let lum = clamp01(f32::abs(0.5 - shade));
     let (r, g, b) = hsl_to_rgb(hue, sat, lum);
-    ((r * 255.) as u8, (g * 255.) as u8, (b * 255.) as u8)
+    (clamp01(r), clamp01(g), clamp01(b))
 }
 
 // convert hsl to rgb, this is based on some code I found online
@@ -73,6 +211,124 @@ fn hue_to_rgb(p: f32, q: f32, h: f32) -> f32 {
     }
 }
 
+// position of a light source used for the relief effect, both angles are in degrees.
+// azimuth is measured counter clockwise from the positive real axis,
+// elevation is the angle above the plane (90 means straight above the image).
+#[derive(Clone)]
+pub struct Light {
+    pub azimuth: f32,
+    pub elevation: f32,
+}
+
+// how much of the color is kept in full shadow
+const AMBIENT: f32 = 0.35;
+// strength of the lambertian (diffuse) reflection
+const DIFFUSE: f32 = 0.65;
+// strength and sharpness of the blinn-phong highlight
+const SPECULAR: f32 = 0.25;
+const SHININESS: i32 = 24;
+// how many pixels of height one iteration corresponds to
+const RELIEF: f32 = 1.5;
+
+// post-processing stage that makes the image look embossed.
+// the smooth iteration count of every point is treated as a height map,
+// normals are estimated with central differences and every pixel is lit
+// with lambert (diffuse) + blinn-phong (specular) shading.
+// the solutions are expected in the same order as for render_image.
+pub fn apply_lighting(image: &mut Rgb32FImage, solutions: &[Solution], field: &Field, light: &Light) {
+    let grid = field.grid as usize;
+    assert_eq!(grid * grid, solutions.len(), "not enough values in solutions");
+
+    let height = |i: usize, j: usize| solutions[i * grid + j].smooth as f32 * RELIEF;
+
+    let azimuth = light.azimuth.to_radians();
+    let elevation = light.elevation.to_radians();
+    let to_light = normalize((
+        elevation.cos() * azimuth.cos(),
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+    ));
+    // the viewer is looking straight down at the image
+    let half = normalize((to_light.0, to_light.1, to_light.2 + 1.));
+
+    for i in 0..grid {
+        for j in 0..grid {
+            let dx = height(usize::min(i + 1, grid - 1), j) - height(i.saturating_sub(1), j);
+            let dy = height(i, usize::min(j + 1, grid - 1)) - height(i, j.saturating_sub(1));
+            let normal = normalize((-dx / 2., -dy / 2., 1.));
+
+            let diffuse = f32::max(0., dot(normal, to_light));
+            let specular = f32::max(0., dot(normal, half)).powi(SHININESS);
+            let shade = AMBIENT + DIFFUSE * diffuse;
+            let highlight = SPECULAR * specular;
+
+            let pixel = image.get_pixel_mut(i as u32, j as u32);
+            for c in pixel.0.iter_mut() {
+                *c = f32::min(1., *c * shade + highlight);
+            }
+        }
+    }
+}
+
+fn dot(a: (f32, f32, f32), b: (f32, f32, f32)) -> f32 {
+    a.0 * b.0 + a.1 * b.1 + a.2 * b.2
+}
+
+fn normalize(v: (f32, f32, f32)) -> (f32, f32, f32) {
+    let len = dot(v, v).sqrt();
+    (v.0 / len, v.1 / len, v.2 / len)
+}
+
 fn clamp01(v: f32) -> f32 {
     f32::min(f32::max(0., v), 1.)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::complex::Complex;
+
+    #[test]
+    fn lighting_follows_the_slope() {
+        // the height grows along the real axis, so the slope faces the negative real axis
+        let field = Field { source: Complex { re: 0., im: 0. }, size: 1., grid: 8 };
+        let solutions: Vec<Solution> = (0..64)
+            .map(|k| Solution { root: Complex { re: 1., im: 0. }, iter: k / 8, smooth: (k / 8) as f64 * 3. })
+            .collect();
+        let lit = |azimuth: f32| {
+            let mut image = Rgb32FImage::from_pixel(8, 8, Rgb([0.8, 0.5, 0.2]));
+            apply_lighting(&mut image, &solutions, &field, &Light { azimuth, elevation: 30. });
+            image
+        };
+        let (facing, away) = (lit(180.), lit(0.));
+        for (a, b) in facing.pixels().zip(away.pixels()) {
+            assert!(a.0[0] > b.0[0]);
+        }
+        for c in facing.pixels().chain(away.pixels()).flat_map(|p| p.0) {
+            assert!((0. ..=1.).contains(&c), "{}", c);
+        }
+    }
+
+    #[test]
+    fn linear_shade_uses_whole_iterations() {
+        let solutions = [Solution { root: Complex { re: 1., im: 0. }, iter: 10, smooth: 9.5 }];
+        assert_eq!(vec![0.5], shade_values(&solutions, 20, &Shading::Linear));
+        assert_eq!(vec![0.475], shade_values(&solutions, 20, &Shading::Smooth));
+    }
+
+    #[test]
+    fn cumulative_histogram_uniform() {
+        let cdf = cumulative_histogram(vec![0, 1, 2, 3].into_iter(), 3);
+        assert_eq!(vec![0.25, 0.5, 0.75, 1.0], cdf);
+    }
+
+    #[test]
+    fn cumulative_histogram_stretches_narrow_range() {
+        // all the points converge in 10 or 11 iterations,
+        // the linear shade would only cover a tenth of the range
+        let cdf = cumulative_histogram(vec![10, 10, 11, 11].into_iter(), 100);
+        assert_eq!(0.0, cdf[9]);
+        assert_eq!(0.5, cdf[10]);
+        assert_eq!(1.0, cdf[11]);
+    }
+}
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::num::{ParseFloatError, ParseIntError};
+use std::sync::atomic::AtomicBool;
+
+use image::ImageResult;
+use serde::Deserialize;
+use tracing::info_span;
+
+use crate::encoding::{encode_image, BitDepth, OutputFormat};
+use crate::export::{export_solutions, DataFormat};
+use crate::math::complex::Complex;
+use crate::math::polynomial::Polynomial;
+use crate::math::precision::{ExactPoint, Precision};
+use crate::rendering::{render_image_f32, Light, RenderOptions, Shading};
+use crate::error::ServerError;
+use crate::error::ServerError::{LimitExceeded, ParsingError, ValidationError};
+use crate::{newton_method_field_precise, newton_method_field_progressive, Field, Solution};
+
+pub const DEFAULT_MAX_ITER: u32 = 100;
+pub const DEFAULT_GRID: u32 = 512;
+
+// metadata key holding the canonical query of the request that produced an image
+pub const REQUEST_METADATA_KEY: &str = "Render request";
+
+// version of what a request renders to, it is part of the cache key so that renders kept on disk,
+// or by downstream caches through their etag, aren't served after the shading or an encoder changed.
+// bump it with every change that changes the bytes of a render
+pub const RENDER_VERSION: u32 = 1;
+
+// upper bounds on what a single request may ask for,
+// they keep one request from taking over the whole machine
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // maximum width and height of the output in pixels
+    pub max_grid: u32,
+    // maximum number of pixels of an image, it bounds the memory of the solutions and image buffers
+    pub max_pixels: u64,
+    pub max_degree: usize,
+    // maximum number of coefficients of a polynomial, trailing zeros included
+    pub max_coefficients: usize,
+    pub max_iter: u32,
+    // maximum number of coefficient evaluations for the whole field,
+    // grid * grid * max_iter * (degree + 1) is used as the estimate
+    pub max_work: u64,
+    // most frames an animation may have
+    pub max_frames: u32,
+    // shortest time a frame of an animation is shown, in milliseconds
+    pub min_delay_ms: u32,
+    // maximum number of coefficient evaluations for all the frames of an animation together
+    pub max_animation_work: u64,
+    // maximum number of pixels of all the frames of an animation together, it bounds the size of the file
+    pub max_animation_pixels: u64,
+    // largest request body (scene or image) the server reads, in bytes
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_grid: 4096,
+            // about 200MB of solutions and image buffers
+            max_pixels: 2048 * 2048,
+            max_degree: 64,
+            max_coefficients: 1024,
+            max_iter: 10_000,
+            max_work: 5_000_000_000,
+            max_frames: 240,
+            min_delay_ms: 20,
+            max_animation_work: 50_000_000_000,
+            // 240 frames of 512x512
+            max_animation_pixels: 240 * 512 * 512,
+            max_body_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+// values used for the parameters a request leaves out
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    pub grid: u32,
+    pub max_iter: u32,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Defaults {
+            grid: DEFAULT_GRID,
+            max_iter: DEFAULT_MAX_ITER,
+        }
+    }
+}
+
+// what a render request produces
+#[derive(Clone)]
+pub enum Output {
+    Image { format: OutputFormat, depth: BitDepth },
+    // the raw solutions instead of a picture
+    Data(DataFormat),
+}
+
+impl Output {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Output::Image { format, .. } => format.content_type(),
+            Output::Data(format) => format.content_type(),
+        }
+    }
+}
+
+// the output of a render and how much work it took
+pub struct Rendered {
+    pub data: Vec<u8>,
+    pub points: u64,
+    pub iterations: u64,
+}
+
+// everything needed to reproduce a render
+#[derive(Clone)]
+pub struct RenderRequest {
+    pub pol: Polynomial,
+    pub field: Field,
+    // tx and ty with the digits field.source can't hold, only kept for views too deep for f64
+    pub exact_source: Option<ExactPoint>,
+    pub max_iter: u32,
+    pub options: RenderOptions,
+    pub output: Output,
+}
+
+impl RenderRequest {
+    // parses the query parameters of a render, see the README for the list of parameters.
+    // unknown parameters are ignored
+    pub fn from_query(params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        RenderRequest::from_query_with_defaults(params, &Defaults::default())
+    }
+
+    // same as from_query, with the given values for the missing size and max_iter
+    pub fn from_query_with_defaults(
+        params: &HashMap<String, String>,
+        defaults: &Defaults,
+    ) -> Result<Self, ServerError> {
+        let pol = parse_pol_param(params)?;
+        let field = parse_field_params(params, defaults.grid)?;
+        let exact_source = params
+            .get("tx")
+            .zip(params.get("ty"))
+            .and_then(|(tx, ty)| ExactPoint::for_field(tx, ty, &field));
+        let max_iter = parse_param_u32(params, "max_iter")?.unwrap_or(defaults.max_iter);
+        let options = RenderOptions {
+            shading: parse_shading_param(params)?,
+            light: parse_light_params(params)?,
+        };
+        let output = parse_output_params(params)?;
+
+        Ok(RenderRequest {
+            pol,
+            field,
+            exact_source,
+            max_iter,
+            options,
+            output,
+        })
+    }
+
+    // checks that the request makes sense and stays within the limits
+    pub fn validate(&self, limits: &Limits) -> Result<(), ServerError> {
+        let invalid = |field: &str, message: &str| {
+            Err(ValidationError {
+                field: field.to_string(),
+                message: message.to_string(),
+            })
+        };
+        let too_big = |field: &str, message: String| {
+            Err(LimitExceeded {
+                field: field.to_string(),
+                message,
+            })
+        };
+
+        if !self.field.source.re.is_finite() {
+            return invalid("tx", "must be a finite number");
+        }
+        if !self.field.source.im.is_finite() {
+            return invalid("ty", "must be a finite number");
+        }
+        if !(self.field.size.is_finite() && self.field.size > 0.) {
+            return invalid("tw", "must be a finite number greater than 0");
+        }
+        if self.field.grid == 0 {
+            return invalid("size", "must be greater than 0");
+        }
+        if self.field.grid > limits.max_grid {
+            return too_big("size", format!("can't be more than {}", limits.max_grid));
+        }
+        let pixels = self.field.grid as u64 * self.field.grid as u64;
+        if pixels > limits.max_pixels {
+            return too_big("size", format!("the image can't have more than {} pixels", limits.max_pixels));
+        }
+        if self.max_iter == 0 {
+            return invalid("max_iter", "must be greater than 0");
+        }
+        if self.max_iter > limits.max_iter {
+            return too_big("max_iter", format!("can't be more than {}", limits.max_iter));
+        }
+        if let Some(light) = &self.options.light {
+            if !light.azimuth.is_finite() {
+                return invalid("light_az", "must be a finite number");
+            }
+            if !light.elevation.is_finite() {
+                return invalid("light_el", "must be a finite number");
+            }
+        }
+
+        if self.pol.coefficients().len() > limits.max_coefficients {
+            return too_big("pol", format!("can't have more than {} coefficients", limits.max_coefficients));
+        }
+        // the derivative of a constant is zero, newton's method would divide by it
+        let degree = match self.pol.degree() {
+            None => return invalid("pol", "the polynomial can't be zero"),
+            Some(0) => return invalid("pol", "the polynomial has to be at least linear"),
+            Some(degree) => degree,
+        };
+        if degree > limits.max_degree {
+            return too_big("pol", format!("the degree can't be more than {}", limits.max_degree));
+        }
+
+        // deep views are solved at a higher precision, which counts as that many f64 evaluations
+        let precision = Precision::for_field(&self.field);
+        let grid = self.field.grid as u64;
+        let work = (grid * grid * self.max_iter as u64 * (degree as u64 + 1)).saturating_mul(precision.cost());
+        if work > limits.max_work {
+            return too_big(
+                "size",
+                format!(
+                    "the render needs up to {} evaluations at {} precision, the limit is {}, reduce size or max_iter",
+                    work,
+                    precision.name(),
+                    limits.max_work
+                ),
+            );
+        }
+
+        Ok(())
+    }
+
+    // the query describing this request with every parameter set explicitly,
+    // so the same render can be reproduced even if the defaults change
+    pub fn to_query(&self) -> String {
+        let coefficients: Vec<String> = self
+            .pol
+            .coefficients()
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        // deep views keep every digit they were given
+        let (tx, ty) = match &self.exact_source {
+            Some(exact) => (exact.re.clone(), exact.im.clone()),
+            None => (self.field.source.re.to_string(), self.field.source.im.to_string()),
+        };
+
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        query
+            .append_pair("pol", &coefficients.join(","))
+            .append_pair("tx", &tx)
+            .append_pair("ty", &ty)
+            .append_pair("tw", &self.field.size.to_string())
+            .append_pair("size", &self.field.grid.to_string())
+            .append_pair("max_iter", &self.max_iter.to_string())
+            .append_pair("shading", self.options.shading.name());
+        if let Some(light) = &self.options.light {
+            query
+                .append_pair("light_az", &light.azimuth.to_string())
+                .append_pair("light_el", &light.elevation.to_string());
+        }
+        match &self.output {
+            Output::Image { format, depth } => {
+                query.append_pair("format", format.name());
+                if let OutputFormat::Jpeg(quality) = format {
+                    query.append_pair("quality", &quality.to_string());
+                }
+                query.append_pair("depth", &depth.bits().to_string());
+            }
+            Output::Data(format) => {
+                query.append_pair("format", format.name());
+            }
+        }
+        query
+            .append_pair("version", env!("CARGO_PKG_VERSION"))
+            .append_pair("render_version", &RENDER_VERSION.to_string());
+        query.finish()
+    }
+
+    // the metadata written into rendered images
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let description = format!(
+            "newton fractal of the polynomial with coefficients {:?}, field starting at {} with size {} \
+             on a {}x{} grid, {} iterations, {} shading",
+            self.pol.coefficients(),
+            self.field.source,
+            self.field.size,
+            self.field.grid,
+            self.field.grid,
+            self.max_iter,
+            self.options.shading.name(),
+        );
+        vec![
+            (
+                "Software".to_string(),
+                format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            ),
+            ("Description".to_string(), description),
+            (REQUEST_METADATA_KEY.to_string(), self.to_query()),
+        ]
+    }
+
+    // reads the request back from the metadata of an image rendered by this crate
+    pub fn from_metadata(metadata: &[(String, String)]) -> Result<Self, ServerError> {
+        let query = metadata
+            .iter()
+            .find(|(key, _)| key == REQUEST_METADATA_KEY)
+            .map(|(_, value)| value)
+            .ok_or_else(|| ServerError::InvalidArgument("image has no render request".to_string()))?;
+        let params = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        RenderRequest::from_query(&params)
+    }
+
+    // solves the field and turns the solutions into the requested output.
+    // this is cpu heavy, so it should not be called from async code directly
+    pub fn render(&self) -> ImageResult<Vec<u8>> {
+        self.render_cancellable(&AtomicBool::new(false))
+            .expect("never cancelled")
+            .map(|rendered| rendered.data)
+    }
+
+    // none if cancel was set before the render finished
+    pub fn render_cancellable(&self, cancel: &AtomicBool) -> Option<ImageResult<Rendered>> {
+        let solutions = self.solve(cancel)?;
+        Some(self.output_of(&solutions))
+    }
+
+    // the solutions of every point of the field, at the precision the view needs
+    pub fn solve(&self, cancel: &AtomicBool) -> Option<Vec<Solution>> {
+        let precision = Precision::for_field(&self.field);
+        info_span!("solve", precision = %precision.name()).in_scope(|| {
+            newton_method_field_precise(&self.pol, &self.field, self.exact_source.as_ref(), self.max_iter, cancel)
+        })
+    }
+
+    // same as render_cancellable, but calls preview with the size and png of every coarse pass first
+    pub fn render_progressive(
+        &self,
+        cancel: &AtomicBool,
+        mut preview: impl FnMut(u32, Vec<u8>),
+    ) -> Option<ImageResult<Rendered>> {
+        // the passes are f64 only, deep views are solved in one go without previews
+        if Precision::for_field(&self.field) != Precision::Double {
+            return self.render_cancellable(cancel);
+        }
+        let solutions = info_span!("solve").in_scope(|| {
+            newton_method_field_progressive(&self.pol, &self.field, self.max_iter, cancel, |coarse, solutions| {
+                let image = render_image_f32(solutions, coarse, self.max_iter, &self.options);
+                // a broken preview is just skipped, the final image reports the error
+                if let Ok(png) = encode_image(&image, &OutputFormat::Png, BitDepth::Eight, &[]) {
+                    preview(coarse.grid, png);
+                }
+            })
+        })?;
+        Some(self.output_of(&solutions))
+    }
+
+    fn output_of(&self, solutions: &[Solution]) -> ImageResult<Rendered> {
+        let data = match &self.output {
+            Output::Image { format, depth } => {
+                let image = info_span!("colour")
+                    .in_scope(|| render_image_f32(solutions, &self.field, self.max_iter, &self.options));
+                info_span!("encode", format = format.name())
+                    .in_scope(|| encode_image(&image, format, *depth, &self.metadata()))
+            }
+            Output::Data(format) => info_span!("encode", format = format.name())
+                .in_scope(|| Ok(export_solutions(solutions, &self.field, self.max_iter, format))),
+        };
+        data.map(|data| Rendered {
+            data,
+            points: solutions.len() as u64,
+            iterations: solutions.iter().map(|s| s.iter as u64).sum(),
+        })
+    }
+}
+
+fn parse_field_params(params: &HashMap<String, String>, default_grid: u32) -> Result<Field, ServerError> {
+    let tsize: f64 = parse_param_f64(params, "tw")?;
+    let tx: f64 = parse_param_f64(params, "tx")?;
+    let ty: f64 = parse_param_f64(params, "ty")?;
+    let grid = parse_param_u32(params, "size")?.unwrap_or(default_grid);
+
+    Ok(Field {
+        source: Complex { re: tx, im: ty },
+        size: tsize,
+        grid,
+    })
+}
+
+fn parse_param_f64(params: &HashMap<String, String>, name: &str) -> Result<f64, ServerError> {
+    if let Some(param) = params.get(name) {
+        return param.parse().map_err(|e: ParseFloatError| ParsingError { message: e.to_string(), field: name.to_string() });
+    }
+    Err(ServerError::InvalidArgument(format!("missing {}", name)))
+}
+
+// optional unsigned parameter
+fn parse_param_u32(params: &HashMap<String, String>, name: &str) -> Result<Option<u32>, ServerError> {
+    params
+        .get(name)
+        .map(|param| {
+            param.parse().map_err(|e: ParseIntError| ParsingError {
+                message: e.to_string(),
+                field: name.to_string(),
+            })
+        })
+        .transpose()
+}
+
+fn parse_shading_param(params: &HashMap<String, String>) -> Result<Shading, ServerError> {
+    match params.get("shading") {
+        None => Ok(Shading::Linear),
+        Some(name) => Shading::from_name(name).ok_or_else(|| ValidationError {
+            message: format!("unknown shading {}", name),
+            field: "shading".to_string(),
+        }),
+    }
+}
+
+// png is used if there is no format parameter
+fn parse_output_params(params: &HashMap<String, String>) -> Result<Output, ServerError> {
+    let name = params.get("format").map(|f| f.as_str()).unwrap_or("png");
+    if let Some(format) = DataFormat::from_name(name) {
+        return Ok(Output::Data(format));
+    }
+
+    let format = OutputFormat::from_name(name).ok_or_else(|| ValidationError {
+        message: format!("unknown format {}", name),
+        field: "format".to_string(),
+    })?;
+    let format = match (format, params.get("quality")) {
+        (OutputFormat::Jpeg(_), Some(quality)) => {
+            let quality: u8 = quality.parse().map_err(|e: ParseIntError| ParsingError {
+                message: e.to_string(),
+                field: "quality".to_string(),
+            })?;
+            if !(1..=100).contains(&quality) {
+                return Err(ValidationError {
+                    message: "quality must be between 1 and 100".to_string(),
+                    field: "quality".to_string(),
+                });
+            }
+            OutputFormat::Jpeg(quality)
+        }
+        _ => format,
+    };
+    let depth = parse_depth_param(params, &format)?;
+
+    Ok(Output::Image { format, depth })
+}
+
+fn parse_depth_param(
+    params: &HashMap<String, String>,
+    format: &OutputFormat,
+) -> Result<BitDepth, ServerError> {
+    let depth = match params.get("depth") {
+        Some(bits) => bits
+            .parse()
+            .ok()
+            .and_then(BitDepth::from_bits)
+            .ok_or_else(|| ValidationError {
+                message: "depth must be one of 8, 16 or 32".to_string(),
+                field: "depth".to_string(),
+            })?,
+        None => format.default_depth(),
+    };
+
+    if !format.supports(depth) {
+        return Err(ValidationError {
+            message: format!("{} doesn't support {} bit depth", format.name(), depth.bits()),
+            field: "depth".to_string(),
+        });
+    }
+    Ok(depth)
+}
+
+// the relief effect is only applied if the light azimuth is given,
+// the elevation is optional and defaults to 45 degrees
+fn parse_light_params(params: &HashMap<String, String>) -> Result<Option<Light>, ServerError> {
+    if !params.contains_key("light_az") {
+        return Ok(None);
+    }
+    let azimuth = parse_param_f64(params, "light_az")? as f32;
+    let elevation = if params.contains_key("light_el") {
+        parse_param_f64(params, "light_el")? as f32
+    } else {
+        45.
+    };
+    Ok(Some(Light { azimuth, elevation }))
+}
+
+fn parse_pol_param(params: &HashMap<String, String>) -> Result<Polynomial, ServerError> {
+    let pol = params
+        .get("pol").ok_or(ServerError::InvalidArgument("missing pol".to_string()))?;
+    let coef_r: Result<Vec<i32>, _> = pol.split(",").map(|s| s.parse::<i32>()).collect();
+    let coef: Vec<i32> = coef_r.map_err(|e: ParseIntError| ParsingError { message: e.to_string(), field: "pol".to_string() })?;
+    Ok(Polynomial::new(coef))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(q: &str) -> HashMap<String, String> {
+        url::form_urlencoded::parse(q.as_bytes()).into_owned().collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let request = RenderRequest::from_query(&query("pol=-1,0,0,1&tx=-1&ty=-1&tw=2")).unwrap();
+        assert_eq!(DEFAULT_GRID, request.field.grid);
+        assert_eq!(DEFAULT_MAX_ITER, request.max_iter);
+        assert!(matches!(
+            request.output,
+            Output::Image { format: OutputFormat::Png, depth: BitDepth::Eight }
+        ));
+    }
+
+    #[test]
+    fn configured_defaults() {
+        let defaults = Defaults { grid: 64, max_iter: 20 };
+        let params = query("pol=-1,0,0,1&tx=-1&ty=-1&tw=2&max_iter=30");
+        let request = RenderRequest::from_query_with_defaults(&params, &defaults).unwrap();
+        assert_eq!(64, request.field.grid);
+        assert_eq!(30, request.max_iter);
+    }
+
+    #[test]
+    fn query_round_trip() {
+        let q = "pol=-1,0,0,1&tx=-1.5&ty=-1&tw=2.25&size=64&max_iter=50&shading=histogram\
+                 &light_az=30&light_el=60&format=jpeg&quality=80";
+        let request = RenderRequest::from_query(&query(q)).unwrap();
+        let canonical = request.to_query();
+        let again = RenderRequest::from_query(&query(&canonical)).unwrap();
+        assert_eq!(canonical, again.to_query());
+        assert_eq!(-1.5, again.field.source.re);
+        assert_eq!(2.25, again.field.size);
+        assert_eq!(64, again.field.grid);
+        assert_eq!(50, again.max_iter);
+        assert!(matches!(again.output, Output::Image { format: OutputFormat::Jpeg(80), .. }));
+    }
+
+    #[test]
+    fn deep_query_keeps_digits() {
+        let tx = "0.0159328677620925661511591039243815722947";
+        let q = format!("pol=-1,0,0,1&tx={}&ty=0.3&tw=1e-24&size=64", tx);
+        let request = RenderRequest::from_query(&query(&q)).unwrap();
+        assert_eq!(Some(tx), request.exact_source.as_ref().map(|exact| exact.re.as_str()));
+        let again = RenderRequest::from_query(&query(&request.to_query())).unwrap();
+        assert_eq!(Some(tx), again.exact_source.as_ref().map(|exact| exact.re.as_str()));
+        let shallow = RenderRequest::from_query(&query("pol=-1,0,0,1&tx=0.25&ty=0.3&tw=1e-3")).unwrap();
+        assert!(shallow.exact_source.is_none());
+    }
+
+    #[test]
+    fn validate_rejects_bad_values() {
+        let limits = Limits::default();
+        for (q, field) in [
+            ("pol=-1,0,1&tx=-1&ty=-1&tw=0", "tw"),
+            ("pol=-1,0,1&tx=-1&ty=-1&tw=NaN", "tw"),
+            ("pol=-1,0,1&tx=inf&ty=-1&tw=2", "tx"),
+            ("pol=0,0,0&tx=-1&ty=-1&tw=2", "pol"),
+            ("pol=3&tx=-1&ty=-1&tw=2", "pol"),
+            ("pol=-1,0,1&tx=-1&ty=-1&tw=2&size=0", "size"),
+            ("pol=-1,0,1&tx=-1&ty=-1&tw=2&max_iter=0", "max_iter"),
+        ] {
+            let request = RenderRequest::from_query(&query(q)).unwrap();
+            match request.validate(&limits) {
+                Err(ValidationError { field: f, .. }) => assert_eq!(field, f, "{}", q),
+                _ => panic!("expected a validation error for {}", q),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_limits() {
+        let limits = Limits {
+            max_work: 1_000_000,
+            ..Limits::default()
+        };
+        let small = RenderRequest::from_query(&query("pol=-1,0,1&tx=-1&ty=-1&tw=2&size=10")).unwrap();
+        assert!(small.validate(&limits).is_ok());
+        let big = RenderRequest::from_query(&query("pol=-1,0,1&tx=-1&ty=-1&tw=2")).unwrap();
+        assert!(matches!(big.validate(&limits), Err(LimitExceeded { .. })));
+        let pol = vec!["1"; 100].join(",");
+        let degree = RenderRequest::from_query(&query(&format!("pol={}&tx=-1&ty=-1&tw=2", pol))).unwrap();
+        assert!(matches!(degree.validate(&Limits::default()), Err(LimitExceeded { .. })));
+        // trailing zeros don't add to the degree, only to the number of coefficients
+        let zeros = format!("pol=-1,1{}&tx=-1&ty=-1&tw=2&size=10", ",0".repeat(100));
+        let zeros = RenderRequest::from_query(&query(&zeros)).unwrap();
+        assert!(zeros.validate(&Limits::default()).is_ok());
+        let few = Limits { max_coefficients: 100, ..Limits::default() };
+        assert!(matches!(zeros.validate(&few), Err(LimitExceeded { .. })));
+        // little work, but the solutions and the image alone would take gigabytes
+        let wide = RenderRequest::from_query(&query("pol=-1,0,1&tx=-1&ty=-1&tw=2&size=4096&max_iter=1")).unwrap();
+        assert!(matches!(wide.validate(&Limits::default()), Err(LimitExceeded { field, .. }) if field == "size"));
+    }
+
+    #[test]
+    fn missing_request_metadata() {
+        let metadata = vec![("Software".to_string(), "gimp".to_string())];
+        assert!(RenderRequest::from_metadata(&metadata).is_err());
+    }
+}
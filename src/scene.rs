@@ -0,0 +1,707 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::num::ParseFloatError;
+use std::path::{self, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::animation::{self, AnimationRequest, Morph, MorphKind, Timeline, DEFAULT_DELAY_MS, MAX_DELAY_MS};
+use crate::math::complex::Complex;
+use crate::math::polynomial::{ComplexPolynomial, Polynomial};
+use crate::math::precision::Precision;
+use crate::math::roots::all_roots;
+use crate::request::{self, Defaults, Limits, RenderRequest};
+use crate::error::ServerError;
+use crate::error::ServerError::{LimitExceeded, ParsingError, ValidationError};
+
+// json description of a render, the body of POST /render.
+// it maps onto the same query parameters as GET /, so both end up
+// as the same RenderRequest and go through the same validation.
+// the same scene can be written as toml and kept in a file, see Scene::from_file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub function: Function,
+    pub view: View,
+    #[serde(default)]
+    pub solver: Solver,
+    #[serde(default)]
+    pub colouring: Colouring,
+    #[serde(default)]
+    pub output: Output,
+    // width and height of the output in pixels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    // turns the scene into an animation, the rest of the scene is its first frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub animation: Option<Animation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Function {
+    // coefficients of the polynomial, starting with the constant term
+    pub pol: Vec<i32>,
+}
+
+// the square of the complex plane that is rendered
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct View {
+    pub tx: Coordinate,
+    pub ty: Coordinate,
+    pub tw: f64,
+}
+
+impl View {
+    pub fn new(tx: f64, ty: f64, tw: f64) -> Self {
+        View {
+            tx: tx.into(),
+            ty: ty.into(),
+            tw,
+        }
+    }
+}
+
+// tx or ty of a view, a number or a string. deep zooms need more digits than a f64 holds,
+// written as a string every digit is kept, like in the query of GET /
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "CoordinateRepr", into = "CoordinateRepr")]
+pub struct Coordinate {
+    pub value: f64,
+    // the digits as they were written, if they were written as a string
+    pub text: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum CoordinateRepr {
+    Number(f64),
+    Text(String),
+}
+
+impl Coordinate {
+    // the coordinate as a query parameter, with every digit it was written with
+    pub fn to_param(&self) -> String {
+        self.text.clone().unwrap_or_else(|| self.value.to_string())
+    }
+}
+
+impl From<f64> for Coordinate {
+    fn from(value: f64) -> Self {
+        Coordinate { value, text: None }
+    }
+}
+
+impl FromStr for Coordinate {
+    type Err = ParseFloatError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(Coordinate {
+            value: text.trim().parse()?,
+            text: Some(text.trim().to_string()),
+        })
+    }
+}
+
+impl TryFrom<CoordinateRepr> for Coordinate {
+    type Error = ParseFloatError;
+
+    fn try_from(repr: CoordinateRepr) -> Result<Self, Self::Error> {
+        match repr {
+            CoordinateRepr::Number(value) => Ok(value.into()),
+            CoordinateRepr::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<Coordinate> for CoordinateRepr {
+    fn from(coordinate: Coordinate) -> Self {
+        match coordinate.text {
+            Some(text) => CoordinateRepr::Text(text),
+            None => CoordinateRepr::Number(coordinate.value),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Solver {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_iter: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Colouring {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shading: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light: Option<Light>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Light {
+    pub azimuth: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elevation: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Animation {
+    pub frames: u32,
+    // milliseconds every frame is shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u32>,
+    pub keyframes: Vec<Keyframe>,
+}
+
+// what changes at a frame, the frames in between are interpolated.
+// the polynomial is given either by complex coefficients or by its roots,
+// the same way in every keyframe. complex numbers are [re, im] pairs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Keyframe {
+    pub frame: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view: Option<View>,
+    // coefficients, starting with the constant term
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pol: Option<Vec<[f64; 2]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roots: Option<Vec<[f64; 2]>>,
+    // how the coefficients or roots get here from the previous keyframe,
+    // a single path for all of them or one each, linear by default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<Path>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Path {
+    Linear,
+    Circular {
+        centre: [f64; 2],
+        // full turns on top of the shorter way around, negative ones go clockwise
+        #[serde(default)]
+        turns: f64,
+    },
+    Bezier {
+        c1: [f64; 2],
+        c2: [f64; 2],
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+}
+
+#[derive(Error, Debug)]
+pub enum SceneError {
+    #[error("can't read scene {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid scene {}: {source}", path.display())]
+    Invalid { path: PathBuf, source: ServerError },
+}
+
+impl Scene {
+    // parses a json scene, errors point to the offending field, e.g. "view.tw"
+    pub fn from_json(data: &[u8]) -> Result<Scene, ServerError> {
+        let de = &mut serde_json::Deserializer::from_slice(data);
+        serde_path_to_error::deserialize(de).map_err(parsing_error)
+    }
+
+    // same as from_json for a toml scene
+    pub fn from_toml(text: &str) -> Result<Scene, ServerError> {
+        serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(parsing_error)
+    }
+
+    // reads a scene file, .toml files are toml and everything else json
+    pub fn from_file(path: &path::Path) -> Result<Scene, SceneError> {
+        let data = fs::read(path).map_err(|source| SceneError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let scene = if is_toml(path) {
+            String::from_utf8(data)
+                .map_err(|e| ServerError::InvalidArgument(e.to_string()))
+                .and_then(|text| Scene::from_toml(&text))
+        } else {
+            Scene::from_json(&data)
+        };
+        scene.map_err(|source| SceneError::Invalid {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    // the query parameters of GET / describing the same render
+    pub fn to_query_params(&self) -> HashMap<String, String> {
+        let coefficients: Vec<String> = self.function.pol.iter().map(|c| c.to_string()).collect();
+
+        let mut params = HashMap::new();
+        params.insert("pol".to_string(), coefficients.join(","));
+        params.insert("tx".to_string(), self.view.tx.to_param());
+        params.insert("ty".to_string(), self.view.ty.to_param());
+        params.insert("tw".to_string(), self.view.tw.to_string());
+        let optional = [
+            ("size", self.size.map(|v| v.to_string())),
+            ("max_iter", self.solver.max_iter.map(|v| v.to_string())),
+            ("shading", self.colouring.shading.clone()),
+            ("light_az", self.colouring.light.as_ref().map(|l| l.azimuth.to_string())),
+            (
+                "light_el",
+                self.colouring.light.as_ref().and_then(|l| l.elevation).map(|v| v.to_string()),
+            ),
+            ("format", self.output.format.clone()),
+            ("quality", self.output.quality.map(|v| v.to_string())),
+            ("depth", self.output.depth.map(|v| v.to_string())),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                params.insert(name.to_string(), value);
+            }
+        }
+        params
+    }
+
+    // validates the scene, errors use the json path of the field instead of the query parameter
+    pub fn to_request(&self, limits: &Limits, defaults: &Defaults) -> Result<RenderRequest, ServerError> {
+        let request = RenderRequest::from_query_with_defaults(&self.to_query_params(), defaults)
+            .map_err(to_json_path)?;
+        request.validate(limits).map_err(to_json_path)?;
+        Ok(request)
+    }
+
+    // validates the animation of the scene, the rest of the scene is the first frame:
+    // its view and polynomial hold until the first keyframe that changes them
+    pub fn to_animation(&self, limits: &Limits, defaults: &Defaults) -> Result<AnimationRequest, ServerError> {
+        let request = self.to_request(limits, defaults)?;
+        let invalid = |field: String, message: &str| ValidationError {
+            field,
+            message: message.to_string(),
+        };
+        let animation = self
+            .animation
+            .as_ref()
+            .ok_or_else(|| invalid("animation".to_string(), "the scene isn't animated"))?;
+        if animation.frames == 0 {
+            return Err(invalid("animation.frames".to_string(), "must be greater than 0"));
+        }
+        if animation.frames > limits.max_frames {
+            return Err(LimitExceeded {
+                field: "animation.frames".to_string(),
+                message: format!("can't be more than {}", limits.max_frames),
+            });
+        }
+        let grid = request.field.grid as u64;
+        let pixels = (grid * grid).saturating_mul(animation.frames as u64);
+        if pixels > limits.max_animation_pixels {
+            return Err(LimitExceeded {
+                field: "animation.frames".to_string(),
+                message: format!(
+                    "the frames have {} pixels together, the limit is {}, reduce frames or size",
+                    pixels, limits.max_animation_pixels
+                ),
+            });
+        }
+        let delay_ms = animation.delay.unwrap_or(DEFAULT_DELAY_MS);
+        if delay_ms < limits.min_delay_ms {
+            return Err(LimitExceeded {
+                field: "animation.delay".to_string(),
+                message: format!("can't be less than {}", limits.min_delay_ms),
+            });
+        }
+        if delay_ms > MAX_DELAY_MS {
+            return Err(invalid("animation.delay".to_string(), &format!("can't be more than {}", MAX_DELAY_MS)));
+        }
+        if !matches!(request.output, request::Output::Image { .. }) {
+            return Err(invalid("output.format".to_string(), "the frames of an animation have to be images"));
+        }
+
+        let mut views = vec![animation::Keyframe {
+            frame: 0,
+            value: self.view.clone(),
+        }];
+        let mut morphs = Vec::new();
+        let mut kind = None;
+        // evaluations of a single frame, like RenderRequest::validate, with the highest degree
+        // any frame has and at the precision of the deepest view
+        let grid = request.field.grid as u64;
+        let cost = animation
+            .keyframes
+            .iter()
+            .filter_map(|k| k.view.as_ref())
+            .chain([&self.view])
+            .map(|view| Precision::for_field(&view.field(request.field.grid)).cost())
+            .max()
+            .unwrap_or(1);
+        let work_of = |degree: usize| (grid * grid * request.max_iter as u64 * (degree as u64 + 1)).saturating_mul(cost);
+        let mut frame_work = work_of(request.pol.degree().unwrap_or(0));
+        for (i, keyframe) in animation.keyframes.iter().enumerate() {
+            let field = |name: &str| format!("animation.keyframes[{}].{}", i, name);
+            if keyframe.frame >= animation.frames {
+                return Err(invalid(field("frame"), "must be less than the number of frames"));
+            }
+            if let Some(view) = &keyframe.view {
+                if !(view.tx.value.is_finite() && view.ty.value.is_finite() && view.tw.is_finite() && view.tw > 0.) {
+                    return Err(invalid(field("view"), "must be finite numbers with a width greater than 0"));
+                }
+                views.push(animation::Keyframe {
+                    frame: keyframe.frame,
+                    value: view.clone(),
+                });
+            }
+
+            let (name, points, this_kind) = match (&keyframe.pol, &keyframe.roots) {
+                (Some(_), Some(_)) => return Err(invalid(field("roots"), "a keyframe has either pol or roots")),
+                (Some(pol), None) => ("pol", pol, MorphKind::Coefficients),
+                (None, Some(roots)) => ("roots", roots, MorphKind::Roots),
+                (None, None) => continue,
+            };
+            if kind.is_some_and(|k| k != this_kind) {
+                return Err(invalid(field(name), "every keyframe has to use either pol or roots"));
+            }
+            kind = Some(this_kind);
+            let max = match this_kind {
+                MorphKind::Coefficients => limits.max_degree + 1,
+                MorphKind::Roots => limits.max_degree,
+            };
+            if points.len() > max {
+                return Err(LimitExceeded {
+                    field: field(name),
+                    message: format!("the degree can't be more than {}", limits.max_degree),
+                });
+            }
+            let degree = match this_kind {
+                MorphKind::Coefficients => points.len().saturating_sub(1),
+                MorphKind::Roots => points.len(),
+            };
+            if work_of(degree) > limits.max_work {
+                return Err(LimitExceeded {
+                    field: field(name),
+                    message: format!(
+                        "a frame needs up to {} evaluations, the limit is {}",
+                        work_of(degree),
+                        limits.max_work
+                    ),
+                });
+            }
+            frame_work = frame_work.max(work_of(degree));
+            if !points.iter().flatten().all(|v| v.is_finite()) {
+                return Err(invalid(field(name), "must be finite numbers"));
+            }
+            let paths = keyframe.paths.iter().map(to_path).collect::<Option<Vec<_>>>();
+            let paths = paths.ok_or_else(|| invalid(field("paths"), "must be finite numbers"))?;
+            if paths.len() > 1 && paths.len() != points.len() {
+                return Err(invalid(field("paths"), "needs a single path or one for every point"));
+            }
+            morphs.push(animation::Keyframe {
+                frame: keyframe.frame,
+                value: Morph {
+                    points: points.iter().map(|p| complex(*p)).collect(),
+                    paths,
+                },
+            });
+        }
+
+        let work = frame_work.saturating_mul(animation.frames as u64);
+        if work > limits.max_animation_work {
+            return Err(LimitExceeded {
+                field: "animation.frames".to_string(),
+                message: format!(
+                    "the animation needs up to {} evaluations, the limit is {}, reduce frames, size or max_iter",
+                    work, limits.max_animation_work
+                ),
+            });
+        }
+
+        // the polynomial of the scene is the first frame, unless a keyframe says otherwise
+        let morph = kind.map(|kind| {
+            if !morphs.iter().any(|k| k.frame == 0) {
+                let pol = ComplexPolynomial::from(&Polynomial::new(self.function.pol.clone()));
+                let points = match kind {
+                    MorphKind::Coefficients => pol.coefficients().to_vec(),
+                    MorphKind::Roots => all_roots(&pol),
+                };
+                morphs.push(animation::Keyframe {
+                    frame: 0,
+                    value: Morph { points, paths: vec![] },
+                });
+            }
+            (kind, Timeline::new(morphs).expect("there is a keyframe"))
+        });
+        // a view keyframe at frame 0 replaces the view of the scene
+        if views.iter().filter(|k| k.frame == 0).count() > 1 {
+            views.remove(0);
+        }
+
+        Ok(AnimationRequest {
+            request,
+            frames: animation.frames,
+            delay_ms,
+            views: Timeline::new(views).expect("there is a keyframe"),
+            morph,
+        })
+    }
+}
+
+fn complex([re, im]: [f64; 2]) -> Complex {
+    Complex { re, im }
+}
+
+// none if the numbers aren't finite
+fn to_path(path: &Path) -> Option<animation::Path> {
+    let finite = |points: &[[f64; 2]]| points.iter().flatten().all(|v| v.is_finite());
+    match path {
+        Path::Linear => Some(animation::Path::Linear),
+        Path::Circular { centre, turns } if finite(&[*centre]) && turns.is_finite() => {
+            Some(animation::Path::Circular {
+                centre: complex(*centre),
+                turns: *turns,
+            })
+        }
+        Path::Bezier { c1, c2 } if finite(&[*c1, *c2]) => Some(animation::Path::Bezier {
+            c1: complex(*c1),
+            c2: complex(*c2),
+        }),
+        _ => None,
+    }
+}
+
+// whether the file looks like a scene, by its extension
+pub fn is_scene_file(path: &path::Path) -> bool {
+    is_toml(path) || path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+fn is_toml(path: &path::Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+fn parsing_error<E: ToString>(e: serde_path_to_error::Error<E>) -> ServerError {
+    let field = e.path().to_string();
+    ParsingError {
+        field: if field == "." { String::new() } else { field },
+        message: e.into_inner().to_string(),
+    }
+}
+
+fn to_json_path(error: ServerError) -> ServerError {
+    match error {
+        ParsingError { field, message } => ParsingError {
+            field: json_path(&field).to_string(),
+            message,
+        },
+        ValidationError { field, message } => ValidationError {
+            field: json_path(&field).to_string(),
+            message,
+        },
+        LimitExceeded { field, message } => LimitExceeded {
+            field: json_path(&field).to_string(),
+            message,
+        },
+        e => e,
+    }
+}
+
+// json path of a field in the scene for a query parameter
+fn json_path(param: &str) -> &str {
+    match param {
+        "pol" => "function.pol",
+        "tx" => "view.tx",
+        "ty" => "view.ty",
+        "tw" => "view.tw",
+        "max_iter" => "solver.max_iter",
+        "shading" => "colouring.shading",
+        "light_az" => "colouring.light.azimuth",
+        "light_el" => "colouring.light.elevation",
+        "format" => "output.format",
+        "quality" => "output.quality",
+        "depth" => "output.depth",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::DEFAULT_GRID;
+
+    #[test]
+    fn minimal_scene() {
+        let json = br#"{"function": {"pol": [-1, 0, 0, 1]}, "view": {"tx": -1, "ty": -1, "tw": 2}}"#;
+        let request = Scene::from_json(json).unwrap().to_request(&Limits::default(), &Defaults::default()).unwrap();
+        assert_eq!(DEFAULT_GRID, request.field.grid);
+        assert_eq!(&[-1, 0, 0, 1], request.pol.coefficients());
+    }
+
+    #[test]
+    fn toml_scene() {
+        let toml = r#"
+            size = 256
+
+            [function]
+            pol = [-1, 0, 0, 1]
+
+            [view]
+            tx = -1
+            ty = -1.0
+            tw = 2
+
+            [colouring]
+            shading = "histogram"
+            light = { azimuth = 45, elevation = 30 }
+        "#;
+        let json = br#"{"function": {"pol": [-1, 0, 0, 1]}, "view": {"tx": -1, "ty": -1, "tw": 2},
+                        "colouring": {"shading": "histogram", "light": {"azimuth": 45, "elevation": 30}},
+                        "size": 256}"#;
+        assert_eq!(Scene::from_json(json).unwrap(), Scene::from_toml(toml).unwrap());
+
+        let toml = "[function]\npol = [-1, 1]\n[view]\ntx = 0\nty = 0\ntw = \"wide\"\n";
+        match Scene::from_toml(toml) {
+            Err(ParsingError { field, .. }) => assert_eq!("view.tw", field),
+            _ => panic!("expected a parsing error"),
+        }
+    }
+
+    #[test]
+    fn animated_scene() {
+        let toml = r#"
+            size = 32
+
+            [function]
+            pol = [-1, 0, 0, 1]
+
+            [view]
+            tx = -2
+            ty = -2
+            tw = 4
+
+            [animation]
+            frames = 10
+
+            [[animation.keyframes]]
+            frame = 9
+            roots = [[1, 0], [-0.5, 0.866], [-0.5, -0.866]]
+            paths = [{ type = "circular", centre = [0, 0], turns = 1 }]
+
+            [[animation.keyframes]]
+            frame = 5
+            view = { tx = -1, ty = -1, tw = 2 }
+        "#;
+        let scene = Scene::from_toml(toml).unwrap();
+        let animation = scene.to_animation(&Limits::default(), &Defaults::default()).unwrap();
+        assert_eq!(10, animation.frames);
+        assert_eq!(DEFAULT_DELAY_MS, animation.delay_ms);
+        assert_eq!(scene.view, animation.views.at(0));
+        assert_eq!(View::new(-1., -1., 2.), animation.views.at(7));
+        let (kind, morph) = animation.morph.unwrap();
+        assert_eq!(MorphKind::Roots, kind);
+        // the first frame has the roots of the scene polynomial
+        assert_eq!(3, morph.at(0).points.len());
+        assert!(morph.at(0).points.contains(&Complex { re: 1., im: 0. }));
+    }
+
+    #[test]
+    fn animation_error_has_path() {
+        let json = br#"{"function": {"pol": [-1, 0, 1]}, "view": {"tx": -1, "ty": -1, "tw": 2},
+                        "animation": {"frames": 4, "keyframes": [{"frame": 1, "pol": [[1, 0], [0, 1]]},
+                                                                 {"frame": 3, "roots": [[1, 0]]}]}}"#;
+        match Scene::from_json(json).unwrap().to_animation(&Limits::default(), &Defaults::default()) {
+            Err(ValidationError { field, .. }) => assert_eq!("animation.keyframes[1].roots", field),
+            _ => panic!("expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn animation_limits() {
+        let scene = |frames: u32, delay: u32| {
+            let json = format!(
+                r#"{{"function": {{"pol": [-1, 0, 1]}}, "view": {{"tx": -1, "ty": -1, "tw": 2}}, "size": 100,
+                    "animation": {{"frames": {}, "delay": {}, "keyframes": []}}}}"#,
+                frames, delay
+            );
+            Scene::from_json(json.as_bytes()).unwrap()
+        };
+        let limits = Limits {
+            max_frames: 10,
+            max_animation_work: 100 * 100 * 100 * 3 * 5,
+            ..Limits::default()
+        };
+        let too_big = |scene: Scene| match scene.to_animation(&limits, &Defaults::default()) {
+            Err(LimitExceeded { field, .. }) => field,
+            _ => panic!("expected a limit error"),
+        };
+        assert!(scene(5, 40).to_animation(&limits, &Defaults::default()).is_ok());
+        assert_eq!("animation.frames", too_big(scene(11, 40)));
+        assert_eq!("animation.frames", too_big(scene(6, 40)));
+        assert_eq!("animation.delay", too_big(scene(5, 10)));
+        // cheap frames, but too many pixels for one file
+        let few_pixels = Limits { max_animation_pixels: 100 * 100 * 4, ..Limits::default() };
+        let pixels = scene(5, 40).to_animation(&few_pixels, &Defaults::default());
+        assert!(matches!(pixels, Err(LimitExceeded { field, .. }) if field == "animation.frames"));
+    }
+
+    #[test]
+    fn deep_view_keeps_digits() {
+        let toml = r#"
+            [function]
+            pol = [-1, 0, 0, 1]
+            [view]
+            tx = "0.0159328677620925661511591039243815722947"
+            ty = 0.3
+            tw = 1e-24
+        "#;
+        let scene = Scene::from_toml(toml).unwrap();
+        assert_eq!(0.015932867762092566, scene.view.tx.value);
+        let request = scene.to_request(&Limits::default(), &Defaults::default()).unwrap();
+        let exact = request.exact_source.unwrap();
+        assert_eq!("0.0159328677620925661511591039243815722947", exact.re);
+        assert_eq!("0.3", exact.im);
+        // written back the same way it was read
+        let json = serde_json::to_value(&scene.view).unwrap();
+        assert_eq!("0.0159328677620925661511591039243815722947", json["tx"]);
+        assert_eq!(0.3, json["ty"]);
+    }
+
+    #[test]
+    fn schema_error_has_path() {
+        let json = br#"{"function": {"pol": [-1, 0, 1]}, "view": {"tx": -1, "ty": "a", "tw": 2}}"#;
+        match Scene::from_json(json) {
+            Err(ParsingError { field, .. }) => assert_eq!("view.ty", field),
+            _ => panic!("expected a parsing error"),
+        }
+    }
+
+    #[test]
+    fn validation_error_has_path() {
+        let json = br#"{"function": {"pol": [-1, 1]}, "view": {"tx": -1, "ty": 1, "tw": 2},
+                        "colouring": {"shading": "sepia"}}"#;
+        match Scene::from_json(json).unwrap().to_request(&Limits::default(), &Defaults::default()) {
+            Err(ValidationError { field, .. }) => assert_eq!("colouring.shading", field),
+            _ => panic!("expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn limit_error_has_path() {
+        let json = br#"{"function": {"pol": [-1, 1]}, "view": {"tx": -1, "ty": 1, "tw": 2},
+                        "solver": {"max_iter": 1000000}}"#;
+        match Scene::from_json(json).unwrap().to_request(&Limits::default(), &Defaults::default()) {
+            Err(LimitExceeded { field, .. }) => assert_eq!("solver.max_iter", field),
+            _ => panic!("expected a limit error"),
+        }
+    }
+}
@@ -1,59 +1,472 @@
-use std::io::Cursor;
-
+use hyper::header::{
+    HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION, RETRY_AFTER,
+    VARY,
+};
+use hyper::body::HttpBody;
 use hyper::{Body, Method, Request, Response, StatusCode, Uri};
-use image::RgbImage;
+use image::ImageResult;
 use std::collections::HashMap;
+use serde_json::json;
+use std::any::Any;
 use std::convert::Infallible;
-use std::num::{ParseFloatError, ParseIntError};
-use thiserror::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, field, info, info_span, Instrument, Span};
 
+use crate::analysis::analyse;
+use crate::cache::{self, CachedRender, RenderCache};
+use crate::config::{
+    Config, DEFAULT_MAX_CONCURRENT_RENDERS, DEFAULT_MAX_QUEUED_PER_CLIENT, DEFAULT_MAX_QUEUED_RENDERS,
+};
+use crate::animation::AnimationRequest;
+use crate::encoding::{read_metadata, AnimationEncoder, AnimationFormat, OutputFormat};
 use crate::math::complex::Complex;
-use crate::math::polynomial::Polynomial;
-use crate::rendering::render_image;
-use crate::{newton_method_field, Field};
-use crate::server::ServerError::ParsingError;
-
-#[derive(Error, Debug)]
-pub enum ServerError {
-    #[error("invalid argument: {0}")]
-    InvalidArgument(String),
-    #[error("passing error on field: {field:?}, message: {message:?}")]
-    ParsingError {
-        field: String,
-        message: String,
-    },
-    #[error("unknown error")]
-    UnknownError,
-}
-
-pub async fn api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    match api_inner(req).await {
-        Ok(r) => Ok(r),
-        Err(e) => Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from(e.to_string()))
-            .unwrap())
-    }
-}
-
-pub async fn api_inner(req: Request<Body>) -> Result<Response<Body>, ServerError> {
+pub use crate::error::ServerError;
+use crate::error::ServerError::ParsingError;
+use crate::metrics::Metrics;
+use crate::queue::RenderQueue;
+use crate::request::{Defaults, Limits, RenderRequest, Rendered};
+use crate::scene::Scene;
+use crate::stream::stream_response;
+use crate::Field;
+
+// the http status an error is answered with
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServerError::InvalidArgument(_) | ParsingError { .. } => StatusCode::BAD_REQUEST,
+            ServerError::ValidationError { .. } | ServerError::LimitExceeded { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ServerError::NotFound => StatusCode::NOT_FOUND,
+            ServerError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ServerError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ServerError::Overloaded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ServerError::EncodingError(_)
+            | ServerError::RenderError(_)
+            | ServerError::UnknownError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// interactive viewer driving the image api, served at /viewer
+const VIEWER_HTML: &str = include_str!("viewer.html");
+
+// state shared by all the requests handled by the server
+pub struct ServerContext {
+    pub limits: Limits,
+    pub defaults: Defaults,
+    pub cache: RenderCache,
+    // bounds the number of renders running at the same time
+    pub queue: Arc<RenderQueue>,
+    // renders still running after this are cancelled
+    pub render_timeout: Option<Duration>,
+    // same for animations
+    pub animation_timeout: Option<Duration>,
+    pub metrics: Metrics,
+}
+
+impl Default for ServerContext {
+    fn default() -> Self {
+        ServerContext {
+            limits: Limits::default(),
+            defaults: Defaults::default(),
+            cache: RenderCache::default(),
+            queue: Arc::new(RenderQueue::new(
+                DEFAULT_MAX_CONCURRENT_RENDERS,
+                DEFAULT_MAX_QUEUED_RENDERS,
+                DEFAULT_MAX_QUEUED_PER_CLIENT,
+            )),
+            render_timeout: None,
+            animation_timeout: None,
+            metrics: Metrics::default(),
+        }
+    }
+}
+
+impl ServerContext {
+    // fails if the cache directory can't be used
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let cache = match &config.cache.dir {
+            Some(dir) => RenderCache::with_dir(config.cache.max_bytes, dir)?,
+            None => RenderCache::new(config.cache.max_bytes),
+        };
+        Ok(ServerContext {
+            limits: config.limits.clone(),
+            defaults: config.defaults.clone(),
+            cache,
+            queue: Arc::new(RenderQueue::new(
+                config.max_concurrent_renders,
+                config.max_queued_renders,
+                config.max_queued_per_client,
+            )),
+            render_timeout: config.render_timeout(),
+            animation_timeout: config.animation_timeout(),
+            metrics: Metrics::default(),
+        })
+    }
+}
+
+// every request gets a span, the render fields are filled in by render_response
+pub async fn api(ctx: Arc<ServerContext>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let route = route_name(req.uri().path());
+    let span = info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        client = %client_id(&req),
+        params = field::Empty,
+        cache = field::Empty,
+        pixels = field::Empty,
+        error = field::Empty,
+    );
+    let started = Instant::now();
+
+    let response = match api_inner(&ctx, req).instrument(span.clone()).await {
+        Ok(r) => r,
+        Err(e) => {
+            span.record("error", e.code());
+            if e.status().is_server_error() {
+                span.in_scope(|| error!(error = %e, "request failed"));
+            }
+            json_error_response(&e)
+        }
+    };
+
+    let status = response.status().as_u16();
+    ctx.metrics.request(route, status);
+    span.in_scope(|| {
+        info!(status, duration_ms = started.elapsed().as_secs_f64() * 1000.0, "request finished")
+    });
+    Ok(response)
+}
+
+// metrics label of a path, paths that are not routes are all "other" so random urls don't add series
+fn route_name(path: &str) -> &'static str {
+    match path {
+        "/" => "/",
+        "/describe" => "/describe",
+        "/render" => "/render",
+        "/animate" => "/animate",
+        "/stream" => "/stream",
+        "/roots" => "/roots",
+        "/viewer" => "/viewer",
+        "/queue" => "/queue",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/metrics" => "/metrics",
+        path if path.starts_with("/tiles/") => "/tiles",
+        _ => "other",
+    }
+}
+
+pub async fn api_inner(ctx: &Arc<ServerContext>, req: Request<Body>) -> Result<Response<Body>, ServerError> {
     let mut response = Response::new(Body::empty());
-    match (req.method(), req.uri().path()) {
+    let method = req.method().clone();
+    let client = client_id(&req);
+    match (&method, req.uri().path()) {
+        // opening the root in a browser shows the viewer
+        (&Method::GET, "/") if req.uri().query().is_none() => {
+            *response.status_mut() = StatusCode::SEE_OTHER;
+            response
+                .headers_mut()
+                .insert(LOCATION, "/viewer".parse().unwrap());
+        }
         (&Method::GET, "/") => {
-            let params = read_query(req.uri());
-            let field = parse_field_params(&params)?;
-            let pol = parse_pol_param(&params)?;
-            let d = handle_image_request(pol, field).await;
-            *response.body_mut() = d.into();
+            let mut params = read_query(req.uri());
+            let negotiated = !params.contains_key("format");
+            if negotiated {
+                let accept = req.headers().get(ACCEPT).and_then(|v| v.to_str().ok());
+                params.insert("format".to_string(), negotiate_format(accept).name().to_string());
+            }
+            let request = info_span!("parse").in_scope(|| {
+                let request = RenderRequest::from_query_with_defaults(&params, &ctx.defaults)?;
+                request.validate(&ctx.limits)?;
+                Ok::<_, ServerError>(request)
+            })?;
+            response = render_response(ctx, &client, request, req.headers().get(IF_NONE_MATCH)).await?;
+            if negotiated {
+                response.headers_mut().insert(VARY, "Accept".parse().unwrap());
+            }
+        }
+        // same as GET /, but streams coarse previews while rendering, see src/stream.rs
+        (&Method::GET, "/stream") => {
+            let mut params = read_query(req.uri());
+            params.entry("format".to_string()).or_insert_with(|| "png".to_string());
+            let request = info_span!("parse").in_scope(|| {
+                let request = RenderRequest::from_query_with_defaults(&params, &ctx.defaults)?;
+                request.validate(&ctx.limits)?;
+                Ok::<_, ServerError>(request)
+            })?;
+            response = stream_response(ctx, &client, request).await?;
+        }
+        // roots, critical points and basin sizes of the polynomial as json, see src/analysis.rs.
+        // the basins are measured over the view, which defaults to the same region as the tiles
+        (&Method::GET, "/roots") => {
+            let mut params = read_query(req.uri());
+            for (name, default) in [("tx", "-2"), ("ty", "-2"), ("tw", "4")] {
+                params.entry(name.to_string()).or_insert_with(|| default.to_string());
+            }
+            let request = info_span!("parse").in_scope(|| {
+                let request = RenderRequest::from_query_with_defaults(&params, &ctx.defaults)?;
+                request.validate(&ctx.limits)?;
+                Ok::<_, ServerError>(request)
+            })?;
+            Span::current().record("params", request.to_query().as_str());
+
+            let _permit = ctx.queue.acquire(&client).await.map_err(|full| ServerError::Overloaded {
+                retry_after: full.retry_after,
+            })?;
+            let analysis = run_cancellable(ctx.render_timeout, move |cancel| {
+                let solutions = request.solve(cancel)?;
+                Some(analyse(&request.pol, &solutions, request.max_iter))
+            })
+            .await?;
+
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            *response.body_mut() = serde_json::to_string(&analysis).unwrap().into();
+        }
+        // reads the render request back from an image uploaded in the body
+        // and redirects to the url that renders it again
+        (&Method::POST, "/describe") => {
+            let body = read_body(req.into_body(), ctx.limits.max_body_bytes).await?;
+            let request = render_request_from_image(&body)?;
+            let location = format!("/?{}", request.to_query());
+            *response.status_mut() = StatusCode::SEE_OTHER;
+            response
+                .headers_mut()
+                .insert(LOCATION, location.parse().unwrap());
+            *response.body_mut() = location.into();
+        }
+        // same as GET /, but the render is described by a json scene in the body,
+        // or a toml one with Content-Type: application/toml
+        (&Method::POST, "/render") => {
+            let toml = is_toml(&req);
+            let body = read_body(req.into_body(), ctx.limits.max_body_bytes).await?;
+            let request = info_span!("parse")
+                .in_scope(|| parse_scene(&body, toml)?.to_request(&ctx.limits, &ctx.defaults))?;
+            response = render_response(ctx, &client, request, None).await?;
+            // the url alone doesn't identify a POST render
+            response.headers_mut().remove(CACHE_CONTROL);
+        }
+        // renders the animation of a scene in the body, like POST /render, into a gif
+        // or with ?format=apng an apng. animations aren't cached, they are rarely asked for twice
+        (&Method::POST, "/animate") => {
+            let format = match read_query(req.uri()).get("format") {
+                None => AnimationFormat::Gif,
+                Some(name) => AnimationFormat::from_name(name).ok_or_else(|| ServerError::ValidationError {
+                    field: "format".to_string(),
+                    message: "must be gif or apng".to_string(),
+                })?,
+            };
+            let toml = is_toml(&req);
+            let body = read_body(req.into_body(), ctx.limits.max_body_bytes).await?;
+            let animation = info_span!("parse")
+                .in_scope(|| parse_scene(&body, toml)?.to_animation(&ctx.limits, &ctx.defaults))?;
+            let grid = animation.request.field.grid as u64;
+            Span::current().record("pixels", grid * grid * animation.frames as u64);
+
+            let _permit = ctx.queue.acquire(&client).await.map_err(|full| ServerError::Overloaded {
+                retry_after: full.retry_after,
+            })?;
+            let started = Instant::now();
+            let job = move |cancel: &AtomicBool| encode_animation(&animation, format, cancel);
+            let rendered = run_cancellable(ctx.animation_timeout, job)
+                .await?
+                .map_err(|e| ServerError::EncodingError(e.to_string()))?;
+            ctx.metrics.render(started.elapsed(), rendered.points, rendered.iterations);
+
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, format.content_type().parse().unwrap());
+            *response.body_mut() = rendered.data.into();
+        }
+        // the process is up
+        (&Method::GET, "/healthz") => {
+            *response.body_mut() = "ok".into();
+        }
+        // the server can take more renders, load balancers should go elsewhere when the queue is full
+        (&Method::GET, "/readyz") => {
+            let queue = ctx.queue.stats();
+            if queue.queued >= queue.max_queued && queue.running >= queue.max_running {
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                *response.body_mut() = "render queue is full".into();
+            } else {
+                *response.body_mut() = "ok".into();
+            }
+        }
+        (&Method::GET, "/metrics") => {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+            *response.body_mut() = ctx.metrics.export(&ctx.queue.stats(), ctx.cache.size()).into();
         }
-        _ => {
-            *response.status_mut() = StatusCode::NOT_FOUND;
+        // how busy the renderers are
+        (&Method::GET, "/queue") => {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            *response.body_mut() = serde_json::to_string(&ctx.queue.stats()).unwrap().into();
         }
+        (&Method::GET, "/viewer") => {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+            *response.body_mut() = VIEWER_HTML.into();
+        }
+        (&Method::GET, path) if path.starts_with("/tiles/") => {
+            let (z, x, y) = parse_tile_path(path).ok_or(ServerError::NotFound)?;
+            let mut params = read_query(req.uri());
+            // the whole base region is tile 0/0/0
+            for (name, default) in [("tx", "-2"), ("ty", "-2"), ("tw", "4")] {
+                params.entry(name.to_string()).or_insert_with(|| default.to_string());
+            }
+            params.insert("size".to_string(), TILE_SIZE.to_string());
+            params.insert("format".to_string(), "png".to_string());
+            let request = info_span!("parse").in_scope(|| {
+                let mut request = RenderRequest::from_query_with_defaults(&params, &ctx.defaults)?;
+                request.field = tile_field(&request.field, z, x, y);
+                // the exact digits describe the base region, not the tile
+                request.exact_source = None;
+                request.validate(&ctx.limits)?;
+                Ok::<_, ServerError>(request)
+            })?;
+            response = render_response(ctx, &client, request, req.headers().get(IF_NONE_MATCH)).await?;
+        }
+        _ => return Err(ServerError::NotFound),
     }
 
     Ok(response)
 }
 
+// reads the whole body, bodies bigger than limit are refused as soon as that is known,
+// from the Content-Length up front or while the chunks arrive
+async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, ServerError> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(ServerError::PayloadTooLarge { limit });
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(ServerError::PayloadTooLarge { limit });
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+// scenes are json, unless they are sent with Content-Type: application/toml
+fn is_toml(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/toml"))
+}
+
+fn parse_scene(body: &[u8], toml: bool) -> Result<Scene, ServerError> {
+    if toml {
+        let text = std::str::from_utf8(body).map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+        Scene::from_toml(text)
+    } else {
+        Scene::from_json(body)
+    }
+}
+
+// none if cancelled before the last frame
+fn encode_animation(
+    animation: &AnimationRequest,
+    format: AnimationFormat,
+    cancel: &AtomicBool,
+) -> Option<ImageResult<Rendered>> {
+    let grid = animation.request.field.grid;
+    let mut data = Vec::new();
+    let result = AnimationEncoder::new(&mut data, format, (grid, grid), animation.frames, animation.delay_ms)
+        .and_then(|mut encoder| {
+            let iterations = match animation.render_frames(cancel, |_, image| encoder.add_frame(&image)) {
+                Some(result) => result?,
+                None => return Ok(None),
+            };
+            encoder.finish().map(|_| Some(iterations))
+        });
+    let points = grid as u64 * grid as u64 * animation.frames as u64;
+    result
+        .map(|done| done.map(|iterations| Rendered { data, points, iterations }))
+        .transpose()
+}
+
+// tiles are rendered in the xyz scheme used by slippy maps (leaflet, openlayers, ...)
+pub const TILE_SIZE: u32 = 256;
+// deeper zooms run out of f64 precision long before this
+const MAX_TILE_ZOOM: u32 = 48;
+
+// parses "/tiles/{z}/{x}/{y}.png", x and y have to be inside the zoom level
+fn parse_tile_path(path: &str) -> Option<(u32, u32, u32)> {
+    let parts: Vec<&str> = path.strip_prefix("/tiles/")?.split('/').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let z: u32 = parts[0].parse().ok()?;
+    let x: u64 = parts[1].parse().ok()?;
+    let y: u64 = parts[2].strip_suffix(".png")?.parse().ok()?;
+    if z > MAX_TILE_ZOOM || x >= 1 << z || y >= 1 << z {
+        return None;
+    }
+    Some((z, x as u32, y as u32))
+}
+
+// the part of the base field covered by a tile, at zoom z the base field
+// is split into 2^z x 2^z tiles, x grows along the real axis and y along the imaginary one
+fn tile_field(base: &Field, z: u32, x: u32, y: u32) -> Field {
+    let size = base.size / (1u64 << z) as f64;
+    Field {
+        source: Complex {
+            re: base.source.re + x as f64 * size,
+            im: base.source.im + y as f64 * size,
+        },
+        size,
+        grid: base.grid,
+    }
+}
+
+// {"error": {"code": "parsing_error", "field": "tw", "message": "..."}},
+// field is null if the error is not about a specific field
+fn json_error_response(error: &ServerError) -> Response<Body> {
+    let body = error_json(error);
+    let mut response = Response::builder()
+        .status(error.status())
+        .header(CONTENT_TYPE, "application/json");
+    if let ServerError::Overloaded { retry_after } = error {
+        response = response.header(RETRY_AFTER, retry_after.as_secs());
+    }
+    response.body(Body::from(body.to_string())).unwrap()
+}
+
+pub(crate) fn error_json(error: &ServerError) -> serde_json::Value {
+    json!({
+        "error": {
+            "code": error.code(),
+            "field": error.field(),
+            "message": error.message(),
+        }
+    })
+}
+
+// the renders of a client share a place in the queue. the address is set by main,
+// requests without one (e.g. in tests) all count as the same client
+fn client_id(req: &Request<Body>) -> String {
+    req.extensions()
+        .get::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
 fn read_query(uri: &Uri) -> HashMap<String, String> {
     uri.query()
         .map(|v| {
@@ -64,50 +477,407 @@ fn read_query(uri: &Uri) -> HashMap<String, String> {
         .unwrap_or_else(HashMap::new)
 }
 
-fn parse_field_params(params: &HashMap<String, String>) -> Result<Field, ServerError> {
-    let tsize: f64 = parse_param_f64(params, "tw")?;
-    let tx: f64 = parse_param_f64(params, "tx")?;
-    let ty: f64 = parse_param_f64(params, "ty")?;
+// the render request stored in the metadata of an image rendered by this crate
+pub fn render_request_from_image(data: &[u8]) -> Result<RenderRequest, ServerError> {
+    let metadata = read_metadata(data).map_err(|e| ParsingError {
+        message: e.to_string(),
+        field: "image".to_string(),
+    })?;
+    RenderRequest::from_metadata(&metadata)
+}
 
-    Ok(Field {
-        source: Complex { re: tx, im: ty },
-        size: tsize,
-        grid: 512,
-    })
+// picks the supported format with the highest quality value in the accept header,
+// ties are resolved by the order of OutputFormat::ALL, so wildcards end up as png
+fn negotiate_format(accept: Option<&str>) -> OutputFormat {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return OutputFormat::Png,
+    };
+
+    // (media type, quality) pairs, e.g. "image/webp;q=0.9"
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|range| {
+            let mut parts = range.split(';').map(|p| p.trim());
+            let media = parts.next().unwrap_or("");
+            let q = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (media, q)
+        })
+        .collect();
+
+    let quality_of = |format: &OutputFormat| {
+        // the most specific matching range decides the quality
+        ["", "image/*", "*/*"].iter().find_map(|wildcard| {
+            let media = if wildcard.is_empty() { format.content_type() } else { wildcard };
+            ranges.iter().find(|(m, _)| m.eq_ignore_ascii_case(media)).map(|(_, q)| *q)
+        })
+    };
+
+    let mut best = (OutputFormat::Png, 0.0);
+    for format in OutputFormat::ALL {
+        if let Some(q) = quality_of(&format) {
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+    }
+    best.0
 }
 
-fn parse_param_f64(params: &HashMap<String, String>, name: &str) -> Result<f64, ServerError> {
-    if let Some(param) = params.get(name) {
-        return param.parse().map_err(|e: ParseFloatError| ParsingError { message: e.to_string(), field: name.to_string() });
+// renders are deterministic, the same canonical request always produces the same bytes.
+// so the canonical query is the cache key and also identifies the content for the etag,
+// a client that already has the render gets a 304 without anything being rendered.
+async fn render_response(
+    ctx: &ServerContext,
+    client: &str,
+    request: RenderRequest,
+    if_none_match: Option<&HeaderValue>,
+) -> Result<Response<Body>, ServerError> {
+    let key = request.to_query();
+    let etag = cache::etag(&key);
+    Span::current().record("params", key.as_str());
+
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(ETAG, etag.parse().unwrap());
+    headers.insert(CACHE_CONTROL, RENDER_CACHE_CONTROL.parse().unwrap());
+
+    if if_none_match.is_some_and(|v| etag_matches(v, &etag)) {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(response);
     }
-    Err(ServerError::InvalidArgument(format!("missing {}", name)))
+
+    let cached = ctx.cache.get(&key);
+    ctx.metrics.cache_lookup(cached.is_some());
+    Span::current().record("cache", if cached.is_some() { "hit" } else { "miss" });
+    let render = match cached {
+        Some(render) => render,
+        None => {
+            let content_type = request.output.content_type().to_string();
+            let _permit = ctx.queue.acquire(client).await.map_err(|full| ServerError::Overloaded {
+                retry_after: full.retry_after,
+            })?;
+            let started = Instant::now();
+            let pixels = request.field.grid as u64 * request.field.grid as u64;
+            Span::current().record("pixels", pixels);
+            let rendered = handle_render_request(request, ctx.render_timeout).await?;
+            ctx.metrics.render(started.elapsed(), rendered.points, rendered.iterations);
+            ctx.cache.insert(
+                key,
+                CachedRender {
+                    data: rendered.data,
+                    content_type,
+                },
+            )
+        }
+    };
+
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, render.content_type.parse().unwrap());
+    *response.body_mut() = render.data.clone().into();
+    Ok(response)
 }
 
-fn parse_pol_param(params: &HashMap<String, String>) -> Result<Polynomial, ServerError> {
-    let pol = params
-        .get("pol").ok_or(ServerError::InvalidArgument("missing pol".to_string()))?;
-    let coef_r: Result<Vec<i32>, _> = pol.split(",").map(|s| s.parse::<i32>()).collect();
-    let coef: Vec<i32> = coef_r.map_err(|e: ParseIntError| ParsingError { message: e.to_string(), field: "pol".to_string() })?;
-    Ok(Polynomial::new(coef))
+// a render never changes for the same url
+const RENDER_CACHE_CONTROL: &str = "public, max-age=86400";
+
+// If-None-Match is either * or a list of (possibly weak) etags
+fn etag_matches(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let value = match if_none_match.to_str() {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    value
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
 }
 
-async fn handle_image_request(pol: Polynomial, field: Field) -> Vec<u8> {
-    let max_iter = 100;
+async fn handle_render_request(
+    request: RenderRequest,
+    deadline: Option<Duration>,
+) -> Result<Rendered, ServerError> {
+    run_cancellable(deadline, move |cancel| request.render_cancellable(cancel))
+        .await?
+        .map_err(|e| ServerError::EncodingError(e.to_string()))
+}
 
+// runs the job on the rayon pool. the job is cancelled when the deadline passes
+// or when this future is dropped, which is what hyper does when the client goes away.
+// the job returns none once it noticed it was cancelled
+async fn run_cancellable<T: Send + 'static>(
+    deadline: Option<Duration>,
+    job: impl FnOnce(&AtomicBool) -> Option<T> + Send + 'static,
+) -> Result<T, ServerError> {
+    let cancel = CancelOnDrop(Arc::new(AtomicBool::new(false)));
+    let flag = cancel.0.clone();
     let (send, recv) = tokio::sync::oneshot::channel();
+    // the spans of the job end up under the span of the request
+    let span = Span::current();
     rayon::spawn(move || {
-        let solutions = newton_method_field(&pol, &field, max_iter);
-        let image = render_image(&solutions, &field, max_iter);
-        let _ = send.send(serialize_image(image));
+        let _span = span.enter();
+        // a panic would otherwise take down the rayon thread and leave the request hanging
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job(&flag)));
+        let _ = send.send(result);
     });
 
-    recv.await.expect("Panic in rayon:spawn")
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, recv)
+            .await
+            .map_err(|_| ServerError::Timeout(deadline))?,
+        None => recv.await,
+    };
+    match result {
+        Ok(Ok(Some(result))) => Ok(result),
+        Ok(Err(panic)) => Err(ServerError::RenderError(panic_message(panic))),
+        // only the guard below cancels, and it is still alive here
+        Ok(Ok(None)) | Err(_) => Err(ServerError::UnknownError),
+    }
+}
+
+// sets the flag when dropped
+pub(crate) struct CancelOnDrop(pub(crate) Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic while rendering".to_string()
+    }
 }
 
-fn serialize_image(image: RgbImage) -> Vec<u8> {
-    let mut data = Cursor::new(Vec::new());
-    image
-        .write_to(&mut data, image::ImageOutputFormat::Jpeg(255))
-        .expect("Unable to write");
-    data.get_ref().clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_path() {
+        assert_eq!(Some((2, 3, 1)), parse_tile_path("/tiles/2/3/1.png"));
+        assert_eq!(None, parse_tile_path("/tiles/2/4/1.png"));
+        assert_eq!(None, parse_tile_path("/tiles/2/3/1.jpg"));
+        assert_eq!(None, parse_tile_path("/tiles/2/3"));
+    }
+
+    #[test]
+    fn tile_field_quarters() {
+        let base = Field {
+            source: Complex { re: -2., im: -2. },
+            size: 4.,
+            grid: TILE_SIZE,
+        };
+        let tile = tile_field(&base, 1, 1, 0);
+        assert_eq!(Complex { re: 0., im: -2. }, tile.source);
+        assert_eq!(2., tile.size);
+    }
+
+    #[test]
+    fn error_statuses() {
+        let parsing = ParsingError { field: "tw".to_string(), message: "invalid".to_string() };
+        assert_eq!(StatusCode::BAD_REQUEST, parsing.status());
+        assert_eq!(Some("tw"), parsing.field());
+        let validation = ServerError::ValidationError { field: "shading".to_string(), message: "".to_string() };
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, validation.status());
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, ServerError::UnknownError.status());
+    }
+
+    #[tokio::test]
+    async fn json_error_body() {
+        let req = Request::get("/?pol=1,x&tx=0&ty=0&tw=1").body(Body::empty()).unwrap();
+        let response = api(Arc::new(ServerContext::default()), req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("parsing_error", body["error"]["code"]);
+        assert_eq!("pol", body["error"]["field"]);
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let ctx = Arc::new(ServerContext {
+            limits: Limits { max_body_bytes: 64, ..Limits::default() },
+            ..ServerContext::default()
+        });
+        let tw = "4".repeat(64);
+        let scene = format!(r#"{{"function": {{"pol": [-1, 0, 0, 1]}}, "view": {{"tx": -2, "ty": -2, "tw": {}}}}}"#, tw);
+        let req = Request::post("/render").body(Body::from(scene.clone())).unwrap();
+        let response = api(ctx.clone(), req).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+        // without a Content-Length the body is cut off while it is read
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in scene.as_bytes().chunks(16) {
+                let _ = sender.send_data(chunk.to_vec().into()).await;
+            }
+        });
+        let response = api(ctx, Request::post("/render").body(body).unwrap()).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+    }
+
+    #[tokio::test]
+    async fn deep_scene_keeps_digits() {
+        let tx = "0.0159328677620925661511591039243815722947";
+        let scene = format!(
+            r#"{{"function": {{"pol": [-1, 0, 0, 1]}}, "view": {{"tx": "{}", "ty": "0.3", "tw": 1e-24}}, "size": 8}}"#,
+            tx
+        );
+        let req = Request::post("/render").body(Body::from(scene)).unwrap();
+        let response = api(Arc::new(ServerContext::default()), req).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let request = render_request_from_image(&body).unwrap();
+        assert_eq!(Some(tx), request.exact_source.as_ref().map(|exact| exact.re.as_str()));
+    }
+
+    #[tokio::test]
+    async fn viewport_of_tiles() {
+        // the tiles a map view on a full hd screen asks for at once, all from the same client
+        let ctx = Arc::new(ServerContext::default());
+        let tiles: Vec<_> = (0..9)
+            .flat_map(|x| (0..6).map(move |y| (x, y)))
+            .map(|(x, y)| {
+                let uri = format!("/tiles/4/{}/{}.png?pol=-1,1&max_iter=1", x, y);
+                tokio::spawn(api(ctx.clone(), Request::get(uri).body(Body::empty()).unwrap()))
+            })
+            .collect();
+        for tile in tiles {
+            assert_eq!(StatusCode::OK, tile.await.unwrap().unwrap().status());
+        }
+    }
+
+    #[tokio::test]
+    async fn render_past_the_deadline() {
+        let params = url::form_urlencoded::parse(b"pol=-1,0,0,0,0,0,0,1&tx=-2&ty=-2&tw=4&size=1024&max_iter=1000")
+            .into_owned()
+            .collect();
+        let request = RenderRequest::from_query(&params).unwrap();
+        let result = handle_render_request(request, Some(Duration::from_millis(1))).await;
+        assert!(matches!(result, Err(ServerError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn overloaded_has_retry_after() {
+        let response = json_error_response(&ServerError::Overloaded { retry_after: Duration::from_secs(3) });
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("3", response.headers().get(RETRY_AFTER).unwrap());
+    }
+
+    #[tokio::test]
+    async fn metrics_count_requests() {
+        let ctx = Arc::new(ServerContext::default());
+        let uri = "/?pol=-1,0,1&tx=-1&ty=-1&tw=2&size=8&format=png";
+        for _ in 0..2 {
+            api(ctx.clone(), Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        }
+        let response = api(ctx, Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("newton_http_requests_total{route=\"/\",status=\"200\"} 2\n"));
+        assert!(body.contains("newton_cache_hits_total 1\n"));
+        assert!(body.contains("newton_points_solved_total 64\n"));
+    }
+
+    #[tokio::test]
+    async fn roots_json() {
+        let req = Request::get("/roots?pol=-1,0,0,1&size=32").body(Body::empty()).unwrap();
+        let response = api(Arc::new(ServerContext::default()), req).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("-1 + x^3", body["polynomial"]["pretty"]);
+        assert_eq!(3, body["roots"].as_array().unwrap().len());
+        assert_eq!(2, body["critical_points"][0]["multiplicity"]);
+    }
+
+    #[tokio::test]
+    async fn animated_gif() {
+        let scene = r#"{"function": {"pol": [-1, 0, 0, 1]}, "view": {"tx": -2, "ty": -2, "tw": 4}, "size": 32,
+                        "animation": {"frames": 3, "keyframes": [{"frame": 2, "roots": [[1, 0], [0, 1], [0, -1]]}]}}"#;
+        let ctx = Arc::new(ServerContext::default());
+        let req = Request::post("/animate").body(Body::from(scene)).unwrap();
+        let response = api(ctx.clone(), req).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("image/gif", response.headers()[CONTENT_TYPE]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(b"GIF89a"));
+        // every frame counts towards the metrics
+        let metrics = ctx.metrics.export(&ctx.queue.stats(), ctx.cache.size());
+        assert!(metrics.contains("newton_points_solved_total 3072\n"));
+
+        let too_long = scene.replace(r#""frames": 3"#, r#""frames": 1000"#);
+        let req = Request::post("/animate?format=apng").body(Body::from(too_long)).unwrap();
+        let response = api(Arc::new(ServerContext::default()), req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("animation.frames", body["error"]["field"]);
+    }
+
+    #[tokio::test]
+    async fn zero_polynomial_is_rejected() {
+        let req = Request::get("/?pol=0,0&tx=0&ty=0&tw=1").body(Body::empty()).unwrap();
+        let response = api(Arc::new(ServerContext::default()), req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+    }
+
+    #[tokio::test]
+    async fn conditional_get() {
+        let ctx = Arc::new(ServerContext::default());
+        let uri = "/?pol=-1,0,1&tx=-1&ty=-1&tw=2&size=8&format=png";
+        let response = api(ctx.clone(), Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let etag = response.headers().get(ETAG).unwrap().clone();
+        assert!(ctx.cache.size() > 0);
+
+        let req = Request::get(uri)
+            .header(IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+        let response = api(ctx, req).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+    }
+
+    #[test]
+    fn etag_lists() {
+        let etag = "\"abc\"";
+        assert!(etag_matches(&HeaderValue::from_static("*"), etag));
+        assert!(etag_matches(&HeaderValue::from_static("\"x\", W/\"abc\""), etag));
+        assert!(!etag_matches(&HeaderValue::from_static("\"abcd\""), etag));
+    }
+
+    #[test]
+    fn negotiate_without_header() {
+        assert_eq!(OutputFormat::Png, negotiate_format(None));
+    }
+
+    #[test]
+    fn negotiate_browser_image_request() {
+        let accept = "image/avif,image/webp,*/*;q=0.8";
+        assert_eq!(OutputFormat::WebP, negotiate_format(Some(accept)));
+    }
+
+    #[test]
+    fn negotiate_wildcard_prefers_png() {
+        assert_eq!(OutputFormat::Png, negotiate_format(Some("image/*")));
+    }
+
+    #[test]
+    fn negotiate_by_quality() {
+        let accept = "image/png;q=0.5, image/jpeg";
+        assert_eq!(OutputFormat::Jpeg(90), negotiate_format(Some(accept)));
+    }
 }
@@ -0,0 +1,167 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Instant;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Response};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::{Instrument, Span};
+
+use crate::cache::CachedRender;
+use crate::error::ServerError;
+use crate::request::{Output, RenderRequest, Rendered};
+use crate::server::{error_json, panic_message, CancelOnDrop, ServerContext};
+
+// what the render thread tells the task writing the events
+enum Message {
+    // a finished "pass" event
+    Pass(String),
+    Done(Result<Rendered, ServerError>),
+}
+
+// progressive render as server-sent events. every coarse pass of the solver is sent
+// as a "pass" event with a png preview, the full image follows as a "done" event,
+// or a "failed" event with the usual json error. images are base64 data urls
+// so they can be put straight into an <img>.
+pub(crate) async fn stream_response(
+    ctx: &Arc<ServerContext>,
+    client: &str,
+    request: RenderRequest,
+) -> Result<Response<Body>, ServerError> {
+    if !matches!(request.output, Output::Image { .. }) {
+        return Err(ServerError::ValidationError {
+            field: "format".to_string(),
+            message: "only images can be streamed".to_string(),
+        });
+    }
+
+    let key = request.to_query();
+    Span::current().record("params", key.as_str());
+    let content_type = request.output.content_type();
+    let grid = request.field.grid;
+
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, "text/event-stream".parse().unwrap());
+    headers.insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+
+    let cached = ctx.cache.get(&key);
+    ctx.metrics.cache_lookup(cached.is_some());
+    if let Some(render) = cached {
+        Span::current().record("cache", "hit");
+        *response.body_mut() = done_event(grid, &render.content_type, &render.data).into();
+        return Ok(response);
+    }
+    Span::current().record("cache", "miss");
+    Span::current().record("pixels", grid as u64 * grid as u64);
+
+    let permit = ctx.queue.acquire(client).await.map_err(|full| ServerError::Overloaded {
+        retry_after: full.retry_after,
+    })?;
+
+    let cancel = CancelOnDrop(Arc::new(AtomicBool::new(false)));
+    let flag = cancel.0.clone();
+    let (send, mut recv) = mpsc::unbounded_channel();
+    let span = Span::current();
+    rayon::spawn(move || {
+        let _span = span.enter();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            request.render_progressive(&flag, |size, png| {
+                let event = event("pass", &json!({"size": size, "image": data_url("image/png", &png)}));
+                let _ = send.send(Message::Pass(event));
+            })
+        }));
+        let result = match result {
+            Ok(Some(Ok(rendered))) => Ok(rendered),
+            Ok(Some(Err(e))) => Err(ServerError::EncodingError(e.to_string())),
+            // cancelled, nobody is listening anymore
+            Ok(None) => return,
+            Err(panic) => Err(ServerError::RenderError(panic_message(panic))),
+        };
+        let _ = send.send(Message::Done(result));
+    });
+
+    let (mut body, stream) = Body::channel();
+    *response.body_mut() = stream;
+    let ctx = ctx.clone();
+    let started = Instant::now();
+    tokio::spawn(
+        async move {
+            // the slot stays taken and the render alive for as long as the stream is open
+            let _permit = permit;
+            let _cancel = cancel;
+            let events = async {
+                while let Some(message) = recv.recv().await {
+                    let event = match message {
+                        Message::Pass(event) => event,
+                        Message::Done(Ok(rendered)) => {
+                            ctx.metrics.render(started.elapsed(), rendered.points, rendered.iterations);
+                            let event = done_event(grid, content_type, &rendered.data);
+                            let render = CachedRender {
+                                data: rendered.data,
+                                content_type: content_type.to_string(),
+                            };
+                            ctx.cache.insert(key.clone(), render);
+                            event
+                        }
+                        Message::Done(Err(e)) => event("failed", &error_json(&e)),
+                    };
+                    // fails when the client went away, the guard then cancels the render
+                    if body.send_data(event.into()).await.is_err() {
+                        return;
+                    }
+                }
+            };
+            if let Some(deadline) = ctx.render_timeout {
+                if tokio::time::timeout(deadline, events).await.is_err() {
+                    let timeout = event("failed", &error_json(&ServerError::Timeout(deadline)));
+                    let _ = body.send_data(timeout.into()).await;
+                }
+            } else {
+                events.await;
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(response)
+}
+
+fn done_event(size: u32, content_type: &str, data: &[u8]) -> String {
+    event("done", &json!({"size": size, "image": data_url(content_type, data)}))
+}
+
+fn event(name: &str, data: &serde_json::Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+fn data_url(content_type: &str, data: &[u8]) -> String {
+    format!("data:{};base64,{}", content_type, STANDARD.encode(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+
+    use crate::server::api;
+
+    #[tokio::test]
+    async fn passes_then_done() {
+        let ctx = Arc::new(ServerContext::default());
+        let uri = "/stream?pol=-1,0,0,1&tx=-1&ty=-1&tw=2&size=128&max_iter=20";
+        let response = api(ctx, Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!("text/event-stream", response.headers().get(CONTENT_TYPE).unwrap());
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let events: Vec<&str> = body.lines().filter_map(|l| l.strip_prefix("event: ")).collect();
+        assert_eq!(vec!["pass", "pass", "done"], events);
+        assert!(body.contains("\"size\":32"));
+        assert!(body.contains("data:image/png;base64,"));
+    }
+}